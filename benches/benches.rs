@@ -5,7 +5,7 @@ use rand_chacha::rand_core::{RngCore, SeedableRng};
 use rand_chacha::ChaChaRng;
 use tempfile::TempDir;
 
-use kvs::{thread_pool::*, KvStore, KvsEngine, SledKvsEngine};
+use kvs::{thread_pool::*, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine};
 
 static SEED: u64 = 0;
 
@@ -35,7 +35,8 @@ fn write_queued_kvstore(c: &mut Criterion) {
         "write_queued_kvstore",
         |b, &&cpu_core_num| {
             // do setup here
-            let engine = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+            let temp_dir = TempDir::new().unwrap();
+            let engine = KvStore::open(temp_dir.path()).unwrap();
             let pool = SharedQueueThreadPool::new(cpu_core_num).unwrap();
             let kv_vec: Vec<(String, String)> =
                 get_random_kv_vec(KEY_LEN, VALUE.to_string().clone(), KV_LEN);
@@ -64,7 +65,8 @@ fn read_queued_kvstore(c: &mut Criterion) {
         "read_queued_kvstore",
         |b, &&cpu_core_num| {
             // do setup here
-            let engine = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+            let temp_dir = TempDir::new().unwrap();
+            let engine = KvStore::open(temp_dir.path()).unwrap();
             let pool = SharedQueueThreadPool::new(cpu_core_num).unwrap();
             let kv_vec: Vec<(String, String)> =
                 get_random_kv_vec(KEY_LEN, VALUE.to_string().clone(), KV_LEN);
@@ -94,10 +96,11 @@ fn write_rayon_kvstore(c: &mut Criterion) {
     let thread_nums = &[1, 2, 4, 8, 16, 32];
 
     c.bench_function_over_inputs(
-        "write_queued_kvstore",
+        "write_rayon_kvstore",
         |b, &&cpu_core_num| {
             // do setup here
-            let engine = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+            let temp_dir = TempDir::new().unwrap();
+            let engine = KvStore::open(temp_dir.path()).unwrap();
             let pool = RayonThreadPool::new(cpu_core_num).unwrap();
             let kv_vec: Vec<(String, String)> =
                 get_random_kv_vec(KEY_LEN, VALUE.to_string().clone(), KV_LEN);
@@ -123,10 +126,11 @@ fn read_rayon_kvstore(c: &mut Criterion) {
     let thread_nums = &[1, 2, 4, 8, 16, 32];
 
     c.bench_function_over_inputs(
-        "read_queued_kvstore",
+        "read_rayon_kvstore",
         |b, &&cpu_core_num| {
             // do setup here
-            let engine = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+            let temp_dir = TempDir::new().unwrap();
+            let engine = KvStore::open(temp_dir.path()).unwrap();
             let pool = RayonThreadPool::new(cpu_core_num).unwrap();
             let kv_vec: Vec<(String, String)> =
                 get_random_kv_vec(KEY_LEN, VALUE.to_string().clone(), KV_LEN);
@@ -152,14 +156,43 @@ fn read_rayon_kvstore(c: &mut Criterion) {
     );
 }
 
+fn read_mmap_kvstore(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "read_mmap_kvstore",
+        |b, &&mmap| {
+            // do setup here
+            let temp_dir = TempDir::new().unwrap();
+            // Small segments, so that nearly all values are in sealed logs.
+            let options = KvStoreOptions::default()
+                .segment_size(1024 * 1024)
+                .mmap_reads(mmap);
+            let engine = KvStore::open_with(temp_dir.path(), options).unwrap();
+            let kv_vec: Vec<(String, String)> =
+                get_random_kv_vec(KEY_LEN, VALUE.to_string().clone(), KV_LEN);
+
+            for (key, value) in kv_vec.clone() {
+                engine.set(key, value).unwrap();
+            }
+
+            b.iter(|| {
+                for (key, value) in kv_vec.clone() {
+                    assert_eq!(engine.get(key).unwrap(), Some(value));
+                }
+            });
+        },
+        &[false, true],
+    );
+}
+
 fn write_rayon_sledkvengine(c: &mut Criterion) {
     let thread_nums = &[1, 2, 4, 8, 16, 32];
 
     c.bench_function_over_inputs(
-        "write_queued_kvstore",
+        "write_rayon_sledkvengine",
         |b, &&cpu_core_num| {
             // do setup here
-            let engine = SledKvsEngine::open(TempDir::new().unwrap().path()).unwrap();
+            let temp_dir = TempDir::new().unwrap();
+            let engine = SledKvsEngine::open(temp_dir.path()).unwrap();
             let pool = RayonThreadPool::new(cpu_core_num).unwrap();
             let kv_vec: Vec<(String, String)> =
                 get_random_kv_vec(KEY_LEN, VALUE.to_string().clone(), KV_LEN);
@@ -185,10 +218,11 @@ fn read_rayon_sledkvengine(c: &mut Criterion) {
     let thread_nums = &[1, 2, 4, 8, 16, 32];
 
     c.bench_function_over_inputs(
-        "read_queued_kvstore",
+        "read_rayon_sledkvengine",
         |b, &&cpu_core_num| {
             // do setup here
-            let engine = SledKvsEngine::open(TempDir::new().unwrap().path()).unwrap();
+            let temp_dir = TempDir::new().unwrap();
+            let engine = SledKvsEngine::open(temp_dir.path()).unwrap();
             let pool = RayonThreadPool::new(cpu_core_num).unwrap();
             let kv_vec: Vec<(String, String)> =
                 get_random_kv_vec(KEY_LEN, VALUE.to_string().clone(), KV_LEN);
@@ -220,6 +254,7 @@ criterion_group!(
     read_queued_kvstore,
     write_rayon_kvstore,
     read_rayon_kvstore,
+    read_mmap_kvstore,
     write_rayon_sledkvengine,
     read_rayon_sledkvengine
 );
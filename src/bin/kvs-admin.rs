@@ -0,0 +1,65 @@
+use clap::{arg, command, Command};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use kvs::KvStore;
+
+/// Exit status of a check that found problems.
+const EXIT_PROBLEMS: i32 = 1;
+
+/// Exit status of a check that could not run.
+const EXIT_ERROR: i32 = 2;
+
+fn main() {
+    env_logger::init();
+
+    let matches = command!()
+        .propagate_version(true)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("verify")
+                .about("Check every record of the logs of a kvs data directory, exiting with status 1 if problems are found and 2 if the check could not run")
+                .arg(
+                    arg!([DIR] "Data directory to check")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .default_value("."),
+                ),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        Some(("verify", sub_matches)) => {
+            let dir = sub_matches.get_one::<PathBuf>("DIR").unwrap();
+            verify(dir)
+        }
+        _ => unreachable!("Exhausted list of subcommands and subcommand_required prevents `None`"),
+    }
+}
+
+fn verify(dir: &Path) {
+    let report = match KvStore::verify(dir) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("unable to verify {}: {}", dir.display(), err);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    println!("logs: {}", report.logs);
+    println!("hint_files: {}", report.hints);
+    println!("keys: {}", report.keys);
+    println!("total_bytes: {}", report.total_bytes);
+    println!("stale_bytes: {}", report.stale_bytes);
+    println!("stale_ratio: {:.3}", report.stale_ratio());
+    if report.live {
+        println!("note: the store could not be locked, its newest log may be in use");
+    }
+    println!("problems: {}", report.problems.len());
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if !report.is_ok() {
+        process::exit(EXIT_PROBLEMS);
+    }
+}
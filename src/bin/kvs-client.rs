@@ -1,11 +1,14 @@
+use clap::error::ErrorKind;
 use clap::{arg, command, Command};
+use std::fs;
 use std::net::SocketAddr;
+use std::process;
 
 // use kvs::KvStore;
-use kvs::{Client, Command as Cmd};
+use kvs::{Client, Command as Cmd, KvsError, WriteBatch};
 
 fn main() {
-    let matches = command!()
+    let mut app = command!()
         .propagate_version(true)
         .subcommand_required(true)
         .arg_required_else_help(true)
@@ -18,19 +21,68 @@ fn main() {
             Command::new("set")
                 .about("Add key-value to database")
                 .arg(arg!([KEY]).required(true))
-                .arg(arg!([VALUE]).required(true)),
+                .arg(arg!([VALUE]).required_unless_present("value-file"))
+                .arg(
+                    arg!(--"value-file" <PATH> "Read the value from a file, which may hold binary data")
+                        .conflicts_with("VALUE"),
+                )
+                .arg(
+                    arg!(--ttl <SECONDS> "Remove the key after the given number of seconds")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"if-absent" "Only set the key if it does not exist")
+                        .conflicts_with_all(["ttl", "if-present"]),
+                )
+                .arg(arg!(--"if-present" "Only set the key if it exists").conflicts_with("ttl"))
+                .arg(
+                    arg!(--"if-version" <VERSION> "Only set the key if its value has this version, exiting with status 2 otherwise")
+                        .value_parser(clap::value_parser!(u64))
+                        .conflicts_with_all(["ttl", "if-absent", "if-present"]),
+                ),
         )
         .subcommand(
             Command::new("get")
                 .about("Get value of key from database")
-                .arg(arg!([KEY]).required(true)),
+                .arg(arg!([KEY]).required(true))
+                .arg(arg!(--meta "Also print the version of the value and when it was written")),
         )
         .subcommand(
             Command::new("rm")
                 .about("remove key-value from database")
+                .arg(arg!([KEY]).required(true))
+                .arg(
+                    arg!(--"if-version" <VERSION> "Only remove the key if its value has this version, exiting with status 2 otherwise")
+                        .value_parser(clap::value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            Command::new("cas")
+                .about("Set a key to a new value only if it has the expected one, exiting with status 2 otherwise")
+                .arg(arg!([KEY]).required(true))
+                .arg(arg!(--expect <VALUE> "Value the key must have. Without it, the key must not exist"))
+                .arg(arg!(--new <VALUE> "Value to set. Without it, the key is removed")),
+        )
+        .subcommand(
+            Command::new("ttl")
+                .about("Get the seconds left before a key expires")
                 .arg(arg!([KEY]).required(true)),
         )
-        .get_matches();
+        .subcommand(
+            Command::new("batch")
+                .about("Apply several writes all or nothing, given as `set KEY VALUE` and `rm KEY`")
+                .arg(arg!([OPS] ...).required(true)),
+        )
+        .subcommand(
+            Command::new("checkpoint")
+                .about("Write a checkpoint of the database to a new directory under the checkpoint directory of the server")
+                .arg(arg!([DIR]).required(true)),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Print the number of keys, the size of the data and the counters of the server"),
+        );
+    let matches = app.get_matches_mut();
 
     let addr = matches.get_one::<String>("addr").unwrap();
 
@@ -38,21 +90,124 @@ fn main() {
 
     let cmd: Cmd = match matches.subcommand() {
         Some(("set", sub_matches)) => {
-            let key: String = sub_matches.get_one::<String>("KEY").unwrap().to_string();
-            let value: String = sub_matches.get_one::<String>("VALUE").unwrap().to_string();
-            Cmd::Set { key, value }
+            let key = sub_matches
+                .get_one::<String>("KEY")
+                .unwrap()
+                .clone()
+                .into_bytes();
+            let value = match sub_matches.get_one::<String>("value-file") {
+                Some(path) => fs::read(path).expect("Unable to read the value file."),
+                None => sub_matches
+                    .get_one::<String>("VALUE")
+                    .unwrap()
+                    .clone()
+                    .into_bytes(),
+            };
+            if sub_matches.get_flag("if-absent") {
+                Cmd::SetIfAbsent { key, value }
+            } else if sub_matches.get_flag("if-present") {
+                Cmd::SetIfPresent { key, value }
+            } else {
+                let ttl_ms = sub_matches
+                    .get_one::<u64>("ttl")
+                    .map(|secs| secs.saturating_mul(1000));
+                let version = sub_matches.get_one::<u64>("if-version").copied();
+                Cmd::Set {
+                    key,
+                    value,
+                    ttl_ms,
+                    version,
+                }
+            }
         }
         Some(("get", sub_matches)) => {
-            let key: String = sub_matches.get_one::<String>("KEY").unwrap().to_string();
-            Cmd::Get { key }
+            let key = sub_matches
+                .get_one::<String>("KEY")
+                .unwrap()
+                .clone()
+                .into_bytes();
+            match sub_matches.get_flag("meta") {
+                true => Cmd::GetMeta { key },
+                false => Cmd::Get { key },
+            }
         }
         Some(("rm", sub_matches)) => {
-            let key: String = sub_matches.get_one::<String>("KEY").unwrap().to_string();
-            Cmd::Rm { key }
+            let key = sub_matches
+                .get_one::<String>("KEY")
+                .unwrap()
+                .clone()
+                .into_bytes();
+            let version = sub_matches.get_one::<u64>("if-version").copied();
+            Cmd::Rm { key, version }
+        }
+        Some(("cas", sub_matches)) => {
+            let key = sub_matches
+                .get_one::<String>("KEY")
+                .unwrap()
+                .clone()
+                .into_bytes();
+            let expected = sub_matches
+                .get_one::<String>("expect")
+                .map(|value| value.clone().into_bytes());
+            let new = sub_matches
+                .get_one::<String>("new")
+                .map(|value| value.clone().into_bytes());
+            Cmd::Cas { key, expected, new }
+        }
+        Some(("ttl", sub_matches)) => {
+            let key = sub_matches
+                .get_one::<String>("KEY")
+                .unwrap()
+                .clone()
+                .into_bytes();
+            Cmd::Ttl { key }
+        }
+        Some(("batch", sub_matches)) => {
+            let ops: Vec<&String> = sub_matches.get_many::<String>("OPS").unwrap().collect();
+            match parse_batch(&ops) {
+                Ok(batch) => Cmd::Batch { batch },
+                Err(msg) => app.error(ErrorKind::InvalidValue, msg).exit(),
+            }
         }
+        Some(("checkpoint", sub_matches)) => {
+            let dir = sub_matches.get_one::<String>("DIR").unwrap().clone();
+            Cmd::Checkpoint { dir }
+        }
+        Some(("stats", _)) => Cmd::Stats,
         _ => unreachable!("Exhausted list of subcommands and subcommand_required prevents `None`"),
     };
 
     let client = Client::new(addr).unwrap();
-    client.send(cmd).unwrap();
+    match client.send(cmd) {
+        // Scripts can tell a failed condition apart from other errors.
+        Err(KvsError::ConditionFailed) => {
+            eprintln!("Condition failed");
+            process::exit(2);
+        }
+        result => result.unwrap(),
+    }
+}
+
+/// Parses the writes of a batch, as in `set KEY VALUE rm KEY`.
+fn parse_batch(ops: &[&String]) -> Result<WriteBatch, String> {
+    let mut batch = WriteBatch::new();
+    let mut ops = ops.iter();
+    while let Some(op) = ops.next() {
+        match op.as_str() {
+            "set" => match (ops.next(), ops.next()) {
+                (Some(key), Some(value)) => {
+                    batch.set(key.as_bytes().to_vec(), value.as_bytes().to_vec());
+                }
+                _ => return Err("`set` in a batch takes a key and a value".to_string()),
+            },
+            "rm" => match ops.next() {
+                Some(key) => {
+                    batch.remove(key.as_bytes().to_vec());
+                }
+                None => return Err("`rm` in a batch takes a key".to_string()),
+            },
+            op => return Err(format!("unknown batch operation `{}`", op)),
+        }
+    }
+    Ok(batch)
 }
@@ -4,6 +4,7 @@ use serde_json::{json, Value};
 use std::env::{self, current_dir};
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::process;
 use std::{net::SocketAddr, path::PathBuf, str::FromStr};
 use strum::{Display, EnumString};
 
@@ -44,6 +45,15 @@ fn main() {
             arg!(--engine <ENGINE_NAME> "Engine used by database, either kvs or sled.")
                 .default_value("default"),
         )
+        .arg(
+            arg!(--sync <POLICY> "When writes are synced to disk: always, os, <N>ms or <N>bytes.")
+                .required(false),
+        )
+        .arg(
+            arg!(--"checkpoint-dir" <DIR> "Directory clients can write checkpoints under. Checkpoints are refused without it.")
+                .value_parser(clap::value_parser!(PathBuf))
+                .required(false),
+        )
         .get_matches();
 
     let addr = {
@@ -57,24 +67,52 @@ fn main() {
         get_engine_type(engine_type).unwrap()
     };
 
+    let sync_policy: Option<SyncPolicy> = matches
+        .get_one::<String>("sync")
+        .map(|sync| sync.parse().expect("Unable to parse sync policy."));
+
+    let checkpoint_dir = matches.get_one::<PathBuf>("checkpoint-dir").cloned();
+
     let thread_pool = NaiveThreadPool::new(THREAD_NUM).unwrap();
 
     info!("server:");
     error!("version number: {}", env!("CARGO_PKG_VERSION"));
     error!("IP address and port: {}", addr);
     error!("storage engine: {}", engine_type);
+    if let Some(sync_policy) = sync_policy {
+        error!("sync policy: {}", sync_policy);
+    }
+    if let Some(checkpoint_dir) = &checkpoint_dir {
+        error!("checkpoint directory: {}", checkpoint_dir.display());
+    }
 
     match engine_type {
-        EngineType::KVS => run_with(
-            KvStore::open(env::current_dir().unwrap()).unwrap(),
-            thread_pool,
-            addr,
-        ),
-        EngineType::SLED => run_with(
-            SledKvsEngine::open(env::current_dir().unwrap()).unwrap(),
-            thread_pool,
-            addr,
-        ),
+        EngineType::KVS => {
+            let mut options = KvStoreOptions::default();
+            if let Some(sync_policy) = sync_policy {
+                options = options.sync_policy(sync_policy);
+            }
+            run_with(
+                KvStore::open_with(env::current_dir().unwrap(), options)
+                    .unwrap_or_else(exit_on_open_error),
+                thread_pool,
+                addr,
+                checkpoint_dir,
+            )
+        }
+        EngineType::SLED => {
+            let mut options = SledOptions::default();
+            if let Some(sync_policy) = sync_policy {
+                options = options.sync_policy(sync_policy);
+            }
+            run_with(
+                SledKvsEngine::open_with(env::current_dir().unwrap(), options)
+                    .unwrap_or_else(exit_on_open_error),
+                thread_pool,
+                addr,
+                checkpoint_dir,
+            )
+        }
         EngineType::DEFAULT => Err(KvsError::UnexpectedConfig),
     }
     .unwrap();
@@ -118,6 +156,7 @@ fn get_engine_type(engine_type: EngineType) -> Result<EngineType> {
         let mut f = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&config_path)?;
         f.write_all(serialized_config.as_bytes())
             .expect("write config.json failed.");
@@ -126,12 +165,21 @@ fn get_engine_type(engine_type: EngineType) -> Result<EngineType> {
     Ok(engine_type)
 }
 
+fn exit_on_open_error<T>(err: KvsError) -> T {
+    error!("unable to open the data directory: {}", err);
+    process::exit(1);
+}
+
 fn run_with<E: KvsEngine, P: ThreadPool>(
     kvs_engine: E,
     thread_pool: P,
     addr: SocketAddr,
+    checkpoint_dir: Option<PathBuf>,
 ) -> Result<()> {
-    let server = Server::new(kvs_engine, thread_pool).unwrap();
+    let mut server = Server::new(kvs_engine, thread_pool).unwrap();
+    if let Some(checkpoint_dir) = checkpoint_dir {
+        server = server.checkpoint_dir(checkpoint_dir);
+    }
     server.listen(addr).unwrap();
     Ok(())
 }
@@ -1,9 +1,13 @@
+use std::io::{self, Write};
 use std::net::{SocketAddr, TcpStream};
 
+use serde::Deserialize;
+
 // use log::{info, error};
 
 use crate::error::{KvsError, Result};
 use crate::util::{Command, Response};
+use crate::EngineStats;
 
 /// a command-line key-value store client
 pub struct Client {
@@ -20,6 +24,16 @@ impl Client {
         Ok(client)
     }
 
+    /// start a transaction on the connection, which the server runs until
+    /// it commits or aborts.
+    pub fn begin(self) -> Result<ClientTransaction> {
+        let mut tx = ClientTransaction {
+            stream: self.stream,
+        };
+        tx.request(Command::Begin)?;
+        Ok(tx)
+    }
+
     /// send a command to the server and handle response.
     pub fn send(self, cmd: Command) -> Result<()> {
         // let serialized_cmd = serde_json::to_string(&cmd).unwrap();
@@ -39,6 +53,24 @@ impl Client {
         match res.res {
             true => {
                 print!("{}", res.info);
+                // Values are written as they are, they may not be text.
+                if let Some(value) = res.value {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(&value)?;
+                    stdout.write_all(b"\n")?;
+                }
+                if let Some(version) = res.version {
+                    println!("version: {}", version);
+                }
+                if let Some(modified_at) = res.modified_at {
+                    println!("modified_at: {}", modified_at);
+                }
+                if let Some(stats) = res.stats {
+                    print_stats(&stats);
+                }
+            }
+            false if res.condition_failed => {
+                return Err(KvsError::ConditionFailed);
             }
             false => {
                 return Err(KvsError::StringError(res.info));
@@ -48,3 +80,78 @@ impl Client {
         Ok(())
     }
 }
+
+/// Prints the figures of a stats command, one `name: value` per line.
+fn print_stats(stats: &EngineStats) {
+    println!("keys: {}", stats.keys);
+    println!("total_bytes: {}", stats.total_bytes);
+    println!("stale_bytes: {}", stats.stale_bytes);
+    println!("compactions: {}", stats.compactions);
+    match stats.last_compaction {
+        Some(duration) => println!("last_compaction_ms: {}", duration.as_millis()),
+        None => println!("last_compaction_ms: none"),
+    }
+    println!("reads: {}", stats.reads);
+    println!("writes: {}", stats.writes);
+}
+
+/// A transaction run by the server over the connection of a `Client`, see
+/// `Command::Begin`. Dropping it before it commits aborts it.
+pub struct ClientTransaction {
+    stream: TcpStream,
+}
+
+impl ClientTransaction {
+    /// Get the value of a key as seen by the transaction. If the key does
+    /// not exist, return None.
+    /// Return `KvsError::Conflict` if the key changed since the transaction
+    /// last read it.
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.request(Command::Get { key })?.value)
+    }
+
+    /// Set the value of a key when the transaction commits.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.request(Command::Set {
+            key,
+            value,
+            ttl_ms: None,
+            version: None,
+        })?;
+        Ok(())
+    }
+
+    /// Remove a given key when the transaction commits.
+    /// Return an error if the key does not exist.
+    pub fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        self.request(Command::Rm { key, version: None })?;
+        Ok(())
+    }
+
+    /// Apply the writes of the transaction all or nothing.
+    /// Return `KvsError::Conflict` if a key it read has changed since, in
+    /// which case nothing is written.
+    pub fn commit(mut self) -> Result<()> {
+        self.request(Command::Commit)?;
+        Ok(())
+    }
+
+    /// Drop the writes of the transaction.
+    pub fn abort(mut self) -> Result<()> {
+        self.request(Command::Abort)?;
+        Ok(())
+    }
+
+    /// Sends a command of the transaction and waits for its response, the
+    /// connection staying open for the next one.
+    fn request(&mut self, cmd: Command) -> Result<Response> {
+        serde_json::to_writer(&self.stream, &cmd)?;
+        let mut de = serde_json::Deserializer::from_reader(&self.stream);
+        let res = Response::deserialize(&mut de)?;
+        match res.res {
+            true => Ok(res),
+            false if res.conflict => Err(KvsError::Conflict),
+            false => Err(KvsError::StringError(res.info)),
+        }
+    }
+}
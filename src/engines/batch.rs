@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::bytes;
+
+/// Writes to several keys that an engine applies all or nothing, in order.
+///
+/// Removing a key that does not exist is not an error in a batch, it does
+/// nothing.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result, WriteBatch};
+/// # fn try_main(store: KvStore) -> Result<()> {
+/// let mut batch = WriteBatch::new();
+/// batch.set(b"user/42".to_vec(), b"alice".to_vec());
+/// batch.set(b"name/alice".to_vec(), b"42".to_vec());
+/// batch.remove(b"name/alicia".to_vec());
+/// store.apply_batch(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+/// A write of a `WriteBatch`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum BatchOp {
+    Set {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        /// time to live in milliseconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_ms: Option<u64>,
+    },
+    Rm {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            ttl_ms: None,
+        });
+        self
+    }
+
+    /// Sets the value of a key for `ttl`, counted from when the batch is
+    /// applied.
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            ttl_ms: Some(ttl.as_millis().try_into().unwrap_or(u64::MAX)),
+        });
+        self
+    }
+
+    /// Removes a key, if it exists.
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Rm { key });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,28 @@
+use std::fs;
+use std::path::Path;
+
+use crate::{KvsError, Result};
+
+/// Creates `dest_dir`, which must not exist yet, and has `fill` write the
+/// checkpoint into it.
+///
+/// The directory is removed again if `fill` fails, so that a failed
+/// checkpoint can't be mistaken for a good one.
+pub(crate) fn create_checkpoint(
+    dest_dir: &Path,
+    fill: impl FnOnce(&Path) -> Result<()>,
+) -> Result<()> {
+    if dest_dir.exists() {
+        return Err(KvsError::StringError(format!(
+            "checkpoint directory {} already exists",
+            dest_dir.display()
+        )));
+    }
+    if let Some(parent) = dest_dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::create_dir(dest_dir)?;
+    fill(dest_dir).inspect_err(|_| {
+        let _ = fs::remove_dir_all(dest_dir);
+    })
+}
@@ -0,0 +1,30 @@
+/// What a conditional write expects of the current value of its key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Condition {
+    /// The key has this value, or does not exist if `None`.
+    Equals(Option<Vec<u8>>),
+    /// The key exists, whatever its value.
+    Present,
+    /// The key exists and its value was set by the write of this version.
+    Version(u64),
+}
+
+impl Condition {
+    /// Whether the value of the key, and not only its existence, is needed
+    /// to check the condition.
+    pub fn needs_value(&self) -> bool {
+        matches!(self, Condition::Equals(Some(_)))
+    }
+
+    /// Whether a key whose version and value are `current`, `None` if it
+    /// does not exist, meets the condition.
+    pub fn holds(&self, current: Option<(u64, &[u8])>) -> bool {
+        match self {
+            Condition::Equals(expected) => expected.as_deref() == current.map(|(_, value)| value),
+            Condition::Present => current.is_some(),
+            Condition::Version(expected) => {
+                current.is_some_and(|(version, _)| version == *expected)
+            }
+        }
+    }
+}
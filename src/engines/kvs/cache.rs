@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Rough memory taken by a cached value besides its key and its bytes.
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// Counters of the value cache of a `KvStore`, returned by
+/// `KvStore::cache_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads served from the cache.
+    pub hits: u64,
+    /// Reads of a key the cache did not have, which went to the logs.
+    pub misses: u64,
+    /// Bytes taken by the cached keys and values.
+    pub size: u64,
+    /// Number of cached values.
+    pub entries: usize,
+}
+
+/// Values of the most recently read keys, up to a number of bytes.
+///
+/// Each value is kept along with the version of the write that set it, and
+/// only served to reads of that version. A value never outlives the write
+/// that replaces it that way, even if a read caches it after the write, and
+/// compactions, which move records without changing their versions, don't
+/// affect it.
+#[derive(Debug)]
+pub(super) struct ValueCache {
+    capacity: u64,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    values: HashMap<Vec<u8>, CachedValue>,
+    /// Keys by the tick of their last use, least recently used first.
+    recency: BTreeMap<u64, Vec<u8>>,
+    next_tick: u64,
+    size: u64,
+}
+
+#[derive(Debug)]
+struct CachedValue {
+    version: u64,
+    value: Vec<u8>,
+    tick: u64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the value of `key` if it is cached for `version`, and marks it
+    /// as the most recently used.
+    pub fn get(&self, key: &[u8], version: u64) -> Option<Vec<u8>> {
+        let value = {
+            let mut state = self.state.lock().unwrap();
+            let tick = state.next_tick;
+            match state.values.get_mut(key) {
+                Some(cached) if cached.version == version => {
+                    let old_tick = cached.tick;
+                    cached.tick = tick;
+                    let value = cached.value.clone();
+                    state.next_tick += 1;
+                    if let Some(key) = state.recency.remove(&old_tick) {
+                        state.recency.insert(tick, key);
+                    }
+                    Some(value)
+                }
+                _ => None,
+            }
+        };
+        let counter = match value {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Caches `value` as the value of `key` for `version`, evicting the least
+    /// recently used values to make room. A value for a newer version is
+    /// kept instead, and values larger than the whole cache are not cached.
+    pub fn insert(&self, key: Vec<u8>, version: u64, value: Vec<u8>) {
+        let size = entry_size(&key, &value);
+        if size > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        match state.values.get(&key) {
+            Some(cached) if cached.version > version => return,
+            Some(_) => state.remove(&key),
+            None => {}
+        }
+        while state.size + size > self.capacity {
+            let oldest = match state.recency.first_key_value() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            state.remove(&oldest);
+        }
+
+        let tick = state.next_tick;
+        state.next_tick += 1;
+        state.size += size;
+        state.recency.insert(tick, key.clone());
+        state.values.insert(
+            key,
+            CachedValue {
+                version,
+                value,
+                tick,
+            },
+        );
+    }
+
+    /// Drops the value of `key`, which a write replaced.
+    pub fn remove(&self, key: &[u8]) {
+        self.state.lock().unwrap().remove(key);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: state.size,
+            entries: state.values.len(),
+        }
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(cached) = self.values.remove(key) {
+            self.recency.remove(&cached.tick);
+            self.size -= entry_size(key, &cached.value);
+        }
+    }
+}
+
+/// Memory taken by a cached value, counting its key twice as it is also in
+/// the recency order.
+fn entry_size(key: &[u8], value: &[u8]) -> u64 {
+    2 * key.len() as u64 + value.len() as u64 + ENTRY_OVERHEAD
+}
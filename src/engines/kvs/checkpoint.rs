@@ -0,0 +1,104 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::hint::hint_path;
+use super::{log_path, read_exact_at, sorted_gen_list, sync_dir, StoreCore};
+use crate::Result;
+
+/// Size of the chunks in which logs are copied.
+const COPY_CHUNK_SIZE: u64 = 64 * 1024;
+
+impl StoreCore {
+    /// Fills `dest_dir` with the logs of the store and their hint files.
+    ///
+    /// The active log is sealed first, so every log up to it is immutable and
+    /// can be hard-linked. The newest one is copied instead, since a store
+    /// opened from the checkpoint appends to it. Compactions wait for the
+    /// checkpoint, so none of the logs is removed in the meantime.
+    pub(super) fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        if self.writer.is_none() {
+            return self.checkpoint_read_only(dest_dir);
+        }
+
+        // Mutex: compaction
+        let _compacting = self.compaction_lock.lock().unwrap();
+        let frozen_gen = {
+            // Mutex: LogWriter
+            let mut writer = self.writer().lock().unwrap();
+            writer.file.sync_data()?;
+            writer.unsynced = 0;
+            let frozen_gen = writer.gen;
+            self.roll_writer(&mut writer, frozen_gen + 1)?;
+            frozen_gen
+        };
+
+        for gen in sorted_gen_list(&self.path)? {
+            if gen > frozen_gen {
+                break;
+            }
+            let log = log_path(&self.path, gen);
+            if gen == frozen_gen {
+                fs::copy(&log, log_path(dest_dir, gen))?;
+            } else {
+                link_or_copy(&log, &log_path(dest_dir, gen))?;
+            }
+            File::open(log_path(dest_dir, gen))?.sync_all()?;
+
+            let hint = hint_path(&self.path, gen);
+            if hint.exists() {
+                link_or_copy(&hint, &hint_path(dest_dir, gen))?;
+                File::open(hint_path(dest_dir, gen))?.sync_all()?;
+            }
+        }
+        sync_dir(dest_dir)?;
+        Ok(())
+    }
+
+    /// Copies the logs as far as the store has loaded them, from the handles
+    /// it keeps open. The writer may append to them or remove them meanwhile.
+    fn checkpoint_read_only(&self, dest_dir: &Path) -> Result<()> {
+        let scanned = self.scanned.lock().unwrap();
+        for (&gen, &end) in scanned.iter() {
+            let file = match self.readers.get(gen) {
+                Some(file) => file,
+                None => File::open(log_path(&self.path, gen))?.into(),
+            };
+            let mut dest = File::create(log_path(dest_dir, gen))?;
+            copy_prefix(&file, end, &mut dest)?;
+            dest.sync_all()?;
+
+            match link_or_copy(&hint_path(&self.path, gen), &hint_path(dest_dir, gen)) {
+                Ok(()) => File::open(hint_path(dest_dir, gen))?.sync_all()?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        sync_dir(dest_dir)?;
+        Ok(())
+    }
+}
+
+/// Hard-links `src` to `dest`, or copies it if they are on different file
+/// systems or links are not supported.
+fn link_or_copy(src: &Path, dest: &Path) -> io::Result<()> {
+    match fs::hard_link(src, dest) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(err),
+        Err(_) => fs::copy(src, dest).map(|_| ()),
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Copies the first `len` bytes of `src` to `dest`, with positioned reads so
+/// that readers sharing the handle are not disturbed.
+fn copy_prefix(src: &File, len: u64, dest: &mut File) -> io::Result<()> {
+    let mut buf = vec![0; COPY_CHUNK_SIZE.min(len) as usize];
+    let mut pos = 0;
+    while pos < len {
+        let chunk = &mut buf[..COPY_CHUNK_SIZE.min(len - pos) as usize];
+        read_exact_at(src, chunk, pos)?;
+        dest.write_all(chunk)?;
+        pos += chunk.len() as u64;
+    }
+    Ok(())
+}
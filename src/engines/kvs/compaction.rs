@@ -0,0 +1,498 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use log::{debug, error, warn};
+
+use super::hint::{hint_path, read_hint, Hint, HintEntry, HintWriter};
+use super::{
+    log_path, sorted_gen_list, sorted_gens_with_extension, sync_dir, CommandPos, KvStoreOptions,
+    StoreCore, FILE_MAGIC, INDEX_CHUNK,
+};
+use crate::engines::expiry::{is_expired, now_millis};
+use crate::Result;
+
+/// Byte counts of one log file.
+#[derive(Debug, Default, Clone, Copy)]
+struct GenStats {
+    total: u64,
+    stale: u64,
+}
+
+/// Tracks how many bytes of each log file are taken by stale records, and
+/// the compactions run since the store was opened.
+#[derive(Debug, Default)]
+pub struct LogStats {
+    gens: BTreeMap<u64, GenStats>,
+    total: u64,
+    stale: u64,
+    compactions: u64,
+    last_compaction: Option<Duration>,
+}
+
+impl LogStats {
+    /// Accounts for a record of `len` bytes appended to the log of `gen`.
+    pub fn add(&mut self, gen: u64, len: u64) {
+        self.gens.entry(gen).or_default().total += len;
+        self.total += len;
+    }
+
+    /// Accounts for a record of the log of `gen` that is no longer needed.
+    pub fn mark_stale(&mut self, gen: u64, len: u64) {
+        if let Some(gen_stats) = self.gens.get_mut(&gen) {
+            gen_stats.stale += len;
+            self.stale += len;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn stale(&self) -> u64 {
+        self.stale
+    }
+
+    /// Returns the number of compactions run, and how long the last one took.
+    pub fn compactions(&self) -> (u64, Option<Duration>) {
+        (self.compactions, self.last_compaction)
+    }
+
+    /// Returns the generation, total and stale bytes of each log.
+    pub fn gen_stats(&self) -> Vec<(u64, u64, u64)> {
+        self.gens
+            .iter()
+            .map(|(&gen, gen_stats)| (gen, gen_stats.total, gen_stats.stale))
+            .collect()
+    }
+
+    /// Builds the stats of logs from their generation, total and stale bytes,
+    /// as returned by `gen_stats`.
+    pub fn from_gen_stats(logs: impl IntoIterator<Item = (u64, u64, u64)>) -> LogStats {
+        let mut stats = LogStats::default();
+        for (gen, total, stale) in logs {
+            stats.gens.insert(gen, GenStats { total, stale });
+            stats.total += total;
+            stats.stale += stale;
+        }
+        stats
+    }
+
+    fn remove_gen(&mut self, gen: u64) {
+        if let Some(gen_stats) = self.gens.remove(&gen) {
+            self.total -= gen_stats.total;
+            self.stale -= gen_stats.stale;
+        }
+    }
+
+    /// Whether enough bytes are stale to make a compaction worth it.
+    pub fn needs_compaction(&self, options: &KvStoreOptions) -> bool {
+        self.stale > 0
+            && (self.stale >= options.compaction_threshold
+                || (self.total >= options.segment_size
+                    && self.stale as f64 >= options.garbage_ratio * self.total as f64))
+    }
+
+    /// Picks the newest generation of the oldest logs to compact.
+    ///
+    /// Only a prefix of the generations is ever compacted, so that dropping a
+    /// removal record can't bring an older value of its key back. It ends at
+    /// the newest log over the garbage ratio, so clean logs after it are left
+    /// alone.
+    fn compaction_end(&self, options: &KvStoreOptions) -> Option<u64> {
+        if !self.needs_compaction(options) {
+            return None;
+        }
+        let over_ratio = |gen_stats: &GenStats| {
+            gen_stats.stale > 0
+                && gen_stats.stale as f64 >= options.garbage_ratio * gen_stats.total as f64
+        };
+        self.gens
+            .iter()
+            .rev()
+            .find(|(_, gen_stats)| over_ratio(gen_stats))
+            .or_else(|| {
+                self.gens
+                    .iter()
+                    .rev()
+                    .find(|(_, gen_stats)| gen_stats.stale > 0)
+            })
+            .map(|(&gen, _)| gen)
+    }
+}
+
+/// Handle to the thread compacting the logs of a `KvStore` in the background.
+/// It also drops expired keys from the index at the sweep interval, and
+/// merges the changes of a bounded index into its key directory file.
+///
+/// Dropping it stops the thread and waits for it. A compaction in progress is
+/// abandoned, which leaves the store consistent.
+#[derive(Debug)]
+pub struct Compactor {
+    tx: Option<Sender<()>>,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub fn spawn(core: Arc<StoreCore>) -> Result<Compactor> {
+        // A single pending request is enough, the thread compacts whatever
+        // has become stale by the time it runs.
+        let (tx, rx) = channel::bounded::<()>(1);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let interval = core.options.sweep_interval;
+        let handle = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::Builder::new()
+                .name("kvs-compaction".to_owned())
+                .spawn(move || loop {
+                    match rx.recv_timeout(interval) {
+                        Ok(()) => {}
+                        // Keys expire without any write, look for them now
+                        // and then.
+                        Err(RecvTimeoutError::Timeout) => {
+                            if let Err(err) = core.expire_all() {
+                                error!("sweep of expired keys failed: {}", err);
+                            }
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    if let Err(err) = core.merge_key_dir(false) {
+                        error!("merge of the key directory failed: {}", err);
+                    }
+                    if let Err(err) = core.compact(&shutdown) {
+                        error!("compaction failed: {}", err);
+                    }
+                })?
+        };
+
+        Ok(Compactor {
+            tx: Some(tx),
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    /// Asks the thread for a compaction, unless one is already pending.
+    pub fn trigger(&self) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.try_send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl StoreCore {
+    /// Rewrites the live entries of the oldest logs into a new generation.
+    ///
+    /// The active generation is sealed first and writes move on to a fresh
+    /// generation, so they keep going while the old entries are copied.
+    ///
+    /// The compacted log is written to a temporary file that only takes its
+    /// final name once it is synced, followed by a hint file recording which
+    /// logs it replaces. The old logs are removed last, oldest first. A crash
+    /// at any point leaves either the old logs or the new one in charge, and
+    /// `recover_compactions` cleans up the rest on the next open.
+    ///
+    /// Old logs that live snapshots still read from are retired instead, and
+    /// removed by a later call once the snapshots are gone.
+    fn compact(&self, shutdown: &AtomicBool) -> Result<()> {
+        if shutdown.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        // Mutex: compaction
+        let _compacting = self.compaction_lock.lock().unwrap();
+        let unneeded = self.snapshots.lock().unwrap().take_unneeded();
+        if !unneeded.is_empty() {
+            remove_logs(&self.path, &unneeded)?;
+        }
+        let end_gen = match self.stats.lock().unwrap().compaction_end(&self.options) {
+            Some(end_gen) => end_gen,
+            None => return Ok(()),
+        };
+
+        let (compaction_gen, max_version) = {
+            // Mutex: LogWriter
+            let mut writer = self.writer().lock().unwrap();
+            let compaction_gen = writer.gen + 1;
+            self.roll_writer(&mut writer, compaction_gen + 1)?;
+            // The removals compacted away may have the highest versions.
+            (compaction_gen, self.last_version.load(Ordering::SeqCst))
+        };
+        let stale_gens: Vec<u64> = sorted_gen_list(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen <= end_gen)
+            .collect();
+
+        let start = Instant::now();
+        let tmp_path = compaction_tmp_path(&self.path, compaction_gen);
+        let mut hint = HintWriter::create(&self.path, compaction_gen, end_gen, max_version)?;
+        let log_len = match self.write_compacted(&tmp_path, end_gen, &mut hint, shutdown) {
+            Ok(Some(log_len)) => log_len,
+            Ok(None) => {
+                debug!("compaction of logs up to {} abandoned", end_gen);
+                remove_if_exists(&tmp_path)?;
+                hint.discard()?;
+                return Ok(());
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                let _ = hint.discard();
+                return Err(err);
+            }
+        };
+        fs::rename(&tmp_path, log_path(&self.path, compaction_gen))?;
+        sync_dir(&self.path)?;
+        let hint = hint.finish(log_len)?;
+        sync_dir(&self.path)?;
+
+        // The moves are applied a chunk at a time, so that neither they nor
+        // the write lock of the index have to be held all at once.
+        let mut moved_entries = hint.entries()?;
+        loop {
+            let chunk: Vec<HintEntry> = moved_entries
+                .by_ref()
+                .take(INDEX_CHUNK)
+                .collect::<io::Result<_>>()?;
+            if chunk.is_empty() {
+                break;
+            }
+            // RwLock: kv_index
+            let mut kv_index = self.kv_index.write().unwrap();
+            // Mutex: LogStats
+            let mut stats = self.stats.lock().unwrap();
+            for entry in chunk {
+                let new_pos = CommandPos {
+                    gen: compaction_gen,
+                    pos: entry.pos,
+                    len: entry.len,
+                    expires_at: entry.expires_at,
+                    version: entry.version,
+                };
+                stats.add(compaction_gen, entry.len);
+                match kv_index.get(&entry.key)? {
+                    // Writes since the copy went to newer logs.
+                    Some(cmd_pos) if cmd_pos.gen <= end_gen => {
+                        kv_index.insert(entry.key, new_pos)?;
+                    }
+                    // Overwritten or removed while copying.
+                    _ => stats.mark_stale(compaction_gen, entry.len),
+                }
+            }
+        }
+        {
+            let mut stats = self.stats.lock().unwrap();
+            for &gen in &stale_gens {
+                stats.remove_gen(gen);
+            }
+        }
+        // The key directory file must not point into the old logs once they
+        // are gone.
+        self.merge_key_dir(true)?;
+
+        {
+            // RwLock: kv_index
+            let _kv_index = self.kv_index.write().unwrap();
+            let mut snapshots = self.snapshots.lock().unwrap();
+            // No entry of the index is left in the old logs but expired ones,
+            // which are never read. Older entries kept for snapshots may be.
+            let removable = snapshots.retire(&stale_gens);
+            self.readers.close_below(end_gen + 1);
+            remove_logs(&self.path, &removable)?;
+        }
+
+        let elapsed = start.elapsed();
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.compactions += 1;
+            stats.last_compaction = Some(elapsed);
+        }
+        debug!(
+            "compacted logs up to {} into {} in {:?}",
+            end_gen, compaction_gen, elapsed
+        );
+        Ok(())
+    }
+
+    /// Copies the live entries of the logs up to `end_gen` to `tmp_path` and
+    /// syncs it, and adds their new locations to `hint`. Expired entries are
+    /// left to the sweeps.
+    ///
+    /// The index is gone through a chunk at a time, so that its entries never
+    /// have to be in memory all at once. Returns the length of the compacted
+    /// log, or `None` if the store is shutting down.
+    fn write_compacted(
+        &self,
+        tmp_path: &Path,
+        end_gen: u64,
+        hint: &mut HintWriter,
+        shutdown: &AtomicBool,
+    ) -> Result<Option<u64>> {
+        let mut compaction_file = File::create(tmp_path)?;
+        compaction_file.write_all(FILE_MAGIC)?;
+
+        let start = Instant::now();
+        let now = now_millis();
+        let mut pos = FILE_MAGIC.len() as u64;
+        let mut after = Bound::Unbounded;
+        loop {
+            let live_entries = self.kv_index.read().unwrap().entries(
+                (after, Bound::Unbounded),
+                false,
+                INDEX_CHUNK,
+            )?;
+            let last_key = match live_entries.last() {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            for (key, cmd_pos) in live_entries {
+                if shutdown.load(Ordering::SeqCst) {
+                    return Ok(None);
+                }
+                if cmd_pos.gen > end_gen || is_expired(cmd_pos.expires_at, now) {
+                    continue;
+                }
+
+                let record = self.readers.read(&self.path, cmd_pos)?;
+                compaction_file.write_all(&record)?;
+                hint.add(&HintEntry {
+                    key,
+                    pos,
+                    len: cmd_pos.len,
+                    expires_at: cmd_pos.expires_at,
+                    version: cmd_pos.version,
+                })?;
+                pos += cmd_pos.len;
+
+                if let Some(rate) = self.options.compaction_rate {
+                    throttle(start, pos, rate);
+                }
+            }
+            after = Bound::Excluded(last_key);
+        }
+        compaction_file.sync_all()?;
+        Ok(Some(pos))
+    }
+}
+
+/// Cleans up after compactions interrupted by a crash, before the logs of
+/// `dir` are loaded.
+///
+/// Temporary files are removed. A compacted log that got its final name and
+/// hint file replaces the logs it was compacted from, so those still around
+/// are removed too. A compacted log whose hint is missing or damaged only
+/// holds copies of entries the older logs have as well, and replaying it after
+/// them gives the same state.
+///
+/// A `read_only` store leaves the files alone and only skips the logs that
+/// are replaced.
+///
+/// Returns the generations of the logs to load, and their valid hint files.
+pub fn recover_compactions(dir: &Path, read_only: bool) -> Result<(Vec<u64>, HashMap<u64, Hint>)> {
+    let mut removed = false;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_tmp = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name.ends_with(".log.tmp") || name.ends_with(".hint.tmp"));
+        if is_tmp && !read_only && path.is_file() {
+            warn!(
+                "removing {} left by an interrupted compaction",
+                path.display()
+            );
+            fs::remove_file(&path)?;
+            removed = true;
+        }
+    }
+
+    let mut gens = sorted_gen_list(dir)?;
+    let mut hints = HashMap::new();
+    for gen in sorted_gens_with_extension(dir, "hint")? {
+        let log_len = match fs::metadata(log_path(dir, gen)) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // The compacted log never got its final name.
+                if !read_only {
+                    fs::remove_file(hint_path(dir, gen))?;
+                    removed = true;
+                }
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(hint) = read_hint(dir, gen)? {
+            if hint.log_len <= log_len {
+                hints.insert(gen, hint);
+            }
+        }
+    }
+
+    // Newer compactions include the logs of older ones.
+    if let Some(compacted_gen) = hints.values().map(|hint| hint.compacted_gen).max() {
+        let (obsolete, live): (Vec<u64>, Vec<u64>) =
+            gens.into_iter().partition(|&gen| gen <= compacted_gen);
+        if !obsolete.is_empty() && !read_only {
+            warn!(
+                "removing logs {:?} left by an interrupted compaction",
+                obsolete
+            );
+            remove_logs(dir, &obsolete)?;
+        }
+        for gen in obsolete {
+            hints.remove(&gen);
+        }
+        gens = live;
+    }
+    if removed {
+        sync_dir(dir)?;
+    }
+    Ok((gens, hints))
+}
+
+/// Removes the logs of `gens` and their hint files, oldest first so that an
+/// entry never outlives the removal record hiding it.
+fn remove_logs(dir: &Path, gens: &[u64]) -> Result<()> {
+    for &gen in gens {
+        fs::remove_file(log_path(dir, gen))?;
+        remove_if_exists(&hint_path(dir, gen))?;
+    }
+    sync_dir(dir)?;
+    Ok(())
+}
+
+fn compaction_tmp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.tmp", gen))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Sleeps as long as `copied` bytes are ahead of `rate` bytes per second.
+fn throttle(start: Instant, copied: u64, rate: u64) {
+    let expected = Duration::from_secs_f64(copied as f64 / rate as f64);
+    if let Some(ahead) = expected.checked_sub(start.elapsed()) {
+        thread::sleep(ahead);
+    }
+}
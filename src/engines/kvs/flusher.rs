@@ -0,0 +1,54 @@
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use log::error;
+
+use super::StoreCore;
+use crate::Result;
+
+/// Handle to the thread syncing the active log of a `KvStore` at a fixed
+/// interval, for `SyncPolicy::EveryMillis`.
+///
+/// Dropping it stops the thread after a last sync.
+#[derive(Debug)]
+pub struct Flusher {
+    tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub fn spawn(core: Arc<StoreCore>, interval: Duration) -> Result<Flusher> {
+        // Nothing is ever sent, dropping the sender stops the thread.
+        let (tx, rx) = channel::bounded::<()>(0);
+        let handle = thread::Builder::new()
+            .name("kvs-flusher".to_owned())
+            .spawn(move || loop {
+                let stop = match rx.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) | Ok(()) => false,
+                    Err(RecvTimeoutError::Disconnected) => true,
+                };
+                if let Err(err) = core.sync_log() {
+                    error!("syncing the log failed: {}", err);
+                }
+                if stop {
+                    break;
+                }
+            })?;
+
+        Ok(Flusher {
+            tx: Some(tx),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
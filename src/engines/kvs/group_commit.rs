@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex};
+
+use log::warn;
+
+use super::keydir::KeyDir;
+use super::record::{check_lengths, Record, RecordType};
+use super::{CommandPos, LogWriter, StoreCore};
+use crate::engines::condition::Condition;
+use crate::engines::expiry::{is_expired, now_millis};
+use crate::{KvsError, Result};
+
+/// A write waiting in the commit queue.
+#[derive(Debug)]
+pub enum WriteOp {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// Sets `key` to `value`, or removes it if `value` is `None`, only if it
+    /// meets `condition`.
+    Conditional {
+        key: Vec<u8>,
+        condition: Condition,
+        value: Option<Vec<u8>>,
+    },
+    /// The writes of a `WriteBatch`, which are not nested. They are enclosed
+    /// in batch markers in the log. None of them is made unless each key of
+    /// `checks` has the version given with it, `None` if it must not exist.
+    Batch {
+        checks: Vec<(Vec<u8>, Option<u64>)>,
+        ops: Vec<WriteOp>,
+    },
+}
+
+/// Queue through which all writes of a `KvStore` are committed.
+///
+/// Concurrent writers queue their operations. The first one to find no commit
+/// in progress becomes the leader: it appends the whole queue to the log with
+/// a single write and a single sync, updates the index and hands every
+/// waiter its result. Writers that queued in the meantime form the next batch.
+#[derive(Debug, Default)]
+pub struct CommitQueue {
+    state: Mutex<QueueState>,
+    committed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    next_ticket: u64,
+    pending: Vec<(u64, WriteOp)>,
+    done: HashMap<u64, Result<()>>,
+    leader_active: bool,
+}
+
+/// A write of a batch once it is encoded, before it reaches the index.
+enum Planned {
+    Set(Vec<u8>, CommandPos),
+    Remove(Vec<u8>, CommandPos),
+    /// A marker of a write batch.
+    Marker(CommandPos),
+}
+
+impl CommitQueue {
+    /// Queues `op` and waits until it is durable according to the sync
+    /// policy and visible to readers.
+    pub fn submit(&self, core: &StoreCore, op: WriteOp) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, op));
+
+        loop {
+            if let Some(result) = state.done.remove(&ticket) {
+                if result.is_ok() {
+                    core.ops.write();
+                }
+                return result;
+            }
+            if state.leader_active {
+                state = self.committed.wait(state).unwrap();
+                continue;
+            }
+
+            state.leader_active = true;
+            let batch = mem::take(&mut state.pending);
+            drop(state);
+            let mut leader = Leader {
+                queue: self,
+                tickets: batch.iter().map(|(ticket, _)| *ticket).collect(),
+                results: None,
+            };
+            leader.results = Some(core.commit_batch(batch));
+            drop(leader);
+            state = self.state.lock().unwrap();
+        }
+    }
+}
+
+/// The commit of a batch by the leader, which hands its results to the
+/// waiters and lets the next leader in when dropped.
+///
+/// If the commit panics, the writes of the batch fail, rather than leave
+/// every later writer waiting for a leader that is gone.
+struct Leader<'a> {
+    queue: &'a CommitQueue,
+    tickets: Vec<u64>,
+    results: Option<Vec<(u64, Result<()>)>>,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        let results = self.results.take().unwrap_or_else(|| {
+            let err = KvsError::StringError("the commit of the batch panicked".to_owned());
+            fail_all(self.tickets.drain(..), err)
+        });
+        let mut state = self
+            .queue
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.leader_active = false;
+        state.done.extend(results);
+        self.queue.committed.notify_all();
+    }
+}
+
+impl StoreCore {
+    /// Appends a batch of writes to the active log and applies them to the
+    /// index, in queue order.
+    fn commit_batch(&self, batch: Vec<(u64, WriteOp)>) -> Vec<(u64, Result<()>)> {
+        let mut results = Vec::with_capacity(batch.len());
+
+        // Mutex: LogWriter, held until the index is updated so that writes
+        // reach the index in log order.
+        let mut writer = self.writer().lock().unwrap();
+        if writer.pos >= self.options.segment_size {
+            let gen = writer.gen + 1;
+            if let Err(err) = self.roll_writer(&mut writer, gen) {
+                return fail_all(batch.into_iter().map(|(ticket, _)| ticket), err);
+            }
+        }
+
+        // Removals and conditions are checked against the index as updated
+        // by the earlier writes of the batch. Expired keys no longer exist.
+        let mut buf = Vec::new();
+        let mut planned: Vec<(u64, Vec<Planned>)> = Vec::with_capacity(batch.len());
+        // Versions are given out in log order.
+        let mut version = self.last_version.load(Ordering::SeqCst);
+        {
+            let kv_index = self.kv_index.read().unwrap();
+            let now = now_millis();
+            // Where the earlier writes of the batch leave each key they
+            // touch, `None` if they remove it. Keys looked up in the index
+            // are kept there too.
+            let mut pending: HashMap<Vec<u8>, Option<CommandPos>> = HashMap::new();
+            for (ticket, op) in batch {
+                let mut writes = Vec::new();
+                let ops = match op {
+                    WriteOp::Batch { checks, ops } => {
+                        // Looked up before the begin marker, so that a write
+                        // batch can't fail halfway.
+                        let removed = ops.iter().filter_map(|op| match op {
+                            WriteOp::Remove { key } => Some(key),
+                            _ => None,
+                        });
+                        let keys = checks.iter().map(|(key, _)| key).chain(removed);
+                        let looked_up = keys
+                            .map(|key| current_pos(&mut pending, &kv_index, key, now))
+                            .collect::<Result<Vec<_>>>();
+                        if let Err(err) = looked_up {
+                            results.push((ticket, Err(err)));
+                            continue;
+                        }
+                        let changed = checks.iter().any(|(key, version)| {
+                            pending[key].map(|cmd_pos| cmd_pos.version) != *version
+                        });
+                        if changed {
+                            results.push((ticket, Err(KvsError::Conflict)));
+                            continue;
+                        }
+                        if ops.is_empty() {
+                            results.push((ticket, Ok(())));
+                            continue;
+                        }
+                        if let Err(err) = ops.iter().try_for_each(check_op_lengths) {
+                            results.push((ticket, Err(err.into())));
+                            continue;
+                        }
+                        let begin = Record::marker(RecordType::BatchBegin);
+                        writes.push(Planned::Marker(append_marker(&mut buf, &writer, &begin)));
+                        ops
+                    }
+                    op => vec![op],
+                };
+                let is_batch = !writes.is_empty();
+
+                for op in ops {
+                    let (record, key, is_set) = match op {
+                        WriteOp::Set {
+                            key,
+                            value,
+                            expires_at,
+                        } => (Record::set(key.clone(), value, expires_at), key, true),
+                        WriteOp::Remove { key } => {
+                            match current_pos(&mut pending, &kv_index, &key, now) {
+                                Ok(Some(_)) => {}
+                                // Write batches skip missing keys.
+                                Ok(None) if is_batch => continue,
+                                Ok(None) => {
+                                    results.push((ticket, Err(KvsError::KeyNotFound)));
+                                    continue;
+                                }
+                                Err(err) => {
+                                    results.push((ticket, Err(err)));
+                                    continue;
+                                }
+                            }
+                            (Record::remove(key.clone()), key, false)
+                        }
+                        WriteOp::Conditional {
+                            key,
+                            condition,
+                            value,
+                        } => {
+                            let current = match current_pos(&mut pending, &kv_index, &key, now) {
+                                Ok(current) => current,
+                                Err(err) => {
+                                    results.push((ticket, Err(err)));
+                                    continue;
+                                }
+                            };
+                            let current_value = match current {
+                                Some(cmd_pos) if condition.needs_value() => {
+                                    match self.read_planned(&writer, &buf, cmd_pos) {
+                                        Ok(value) => Some(value),
+                                        Err(err) => {
+                                            results.push((ticket, Err(err)));
+                                            continue;
+                                        }
+                                    }
+                                }
+                                // Only whether the key exists matters.
+                                Some(_) => Some(Vec::new()),
+                                None => None,
+                            };
+                            let current_version = current.map(|cmd_pos| cmd_pos.version);
+                            if !condition.holds(current_version.zip(current_value.as_deref())) {
+                                results.push((ticket, Err(KvsError::ConditionFailed)));
+                                continue;
+                            }
+                            match value {
+                                Some(value) => (Record::set(key.clone(), value, None), key, true),
+                                None if current.is_some() => {
+                                    (Record::remove(key.clone()), key, false)
+                                }
+                                // Removing a key that is already gone.
+                                None => {
+                                    results.push((ticket, Ok(())));
+                                    continue;
+                                }
+                            }
+                        }
+                        WriteOp::Batch { .. } => unreachable!("write batches are not nested"),
+                    };
+
+                    let record = record.versioned(version + 1, now);
+                    // The lengths of the writes of a batch are checked
+                    // before its begin marker, so only single writes fail
+                    // here.
+                    let cmd_pos = match append(&mut buf, &writer, &record) {
+                        Ok(cmd_pos) => cmd_pos,
+                        Err(err) => {
+                            results.push((ticket, Err(err.into())));
+                            continue;
+                        }
+                    };
+                    version += 1;
+                    pending.insert(key.clone(), Some(cmd_pos).filter(|_| is_set));
+                    writes.push(if is_set {
+                        Planned::Set(key, cmd_pos)
+                    } else {
+                        Planned::Remove(key, cmd_pos)
+                    });
+                }
+
+                if is_batch {
+                    let commit = Record::marker(RecordType::BatchCommit);
+                    writes.push(Planned::Marker(append_marker(&mut buf, &writer, &commit)));
+                }
+                if !writes.is_empty() {
+                    planned.push((ticket, writes));
+                }
+            }
+        }
+        if planned.is_empty() {
+            return results;
+        }
+
+        if let Err(err) = writer.append(&buf, self.options.sync_policy) {
+            // The records may be in the log if it could not be truncated,
+            // and would be replayed on the next open.
+            self.last_version.store(version, Ordering::SeqCst);
+            let tickets = planned.iter().map(|(ticket, _)| *ticket);
+            results.extend(fail_all(tickets, err));
+            return results;
+        }
+
+        // RwLock: kv_index
+        let mut kv_index = self.kv_index.write().unwrap();
+        // Snapshots see the writes up to the last version, all in the index.
+        self.last_version.store(version, Ordering::SeqCst);
+        let mut snapshots = self.snapshots.lock().unwrap();
+        // Mutex: LogStats
+        let mut stats = self.stats.lock().unwrap();
+        for (ticket, writes) in planned {
+            for planned_write in writes {
+                if let (Some(cache), Planned::Set(key, _) | Planned::Remove(key, _)) =
+                    (&self.cache, &planned_write)
+                {
+                    cache.remove(key);
+                }
+                let old_pos = match planned_write {
+                    Planned::Set(key, cmd_pos) => {
+                        if cmd_pos.expires_at.is_some() {
+                            self.has_expiring.store(true, Ordering::SeqCst);
+                        }
+                        stats.add(cmd_pos.gen, cmd_pos.len);
+                        if snapshots.is_live() {
+                            let old_pos = known_pos(kv_index.get(&key));
+                            snapshots.record(&key, cmd_pos.version, old_pos);
+                        }
+                        known_pos(kv_index.insert(key, cmd_pos))
+                    }
+                    Planned::Remove(key, cmd_pos) => {
+                        // The tombstone is only needed until the older entries are gone.
+                        stats.add(cmd_pos.gen, cmd_pos.len);
+                        stats.mark_stale(cmd_pos.gen, cmd_pos.len);
+                        let old_pos = known_pos(kv_index.remove(&key));
+                        snapshots.record(&key, cmd_pos.version, old_pos);
+                        old_pos
+                    }
+                    Planned::Marker(cmd_pos) => {
+                        stats.add(cmd_pos.gen, cmd_pos.len);
+                        stats.mark_stale(cmd_pos.gen, cmd_pos.len);
+                        None
+                    }
+                };
+                if let Some(old_pos) = old_pos {
+                    stats.mark_stale(old_pos.gen, old_pos.len);
+                }
+            }
+            results.push((ticket, Ok(())));
+        }
+        kv_index.note_version(version);
+        if kv_index.needs_freeze() {
+            kv_index.freeze(writer.gen, writer.pos, &stats);
+        }
+        results
+    }
+
+    /// Reads the value at `cmd_pos`, which is either in the logs or in `buf`,
+    /// the bytes `writer` is about to write.
+    fn read_planned(&self, writer: &LogWriter, buf: &[u8], cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        if cmd_pos.gen != writer.gen || cmd_pos.pos < writer.pos {
+            return self
+                .read_from_log(cmd_pos)?
+                .map(|record| record.value)
+                .ok_or(KvsError::UnexpectedCommandType);
+        }
+        let start = (cmd_pos.pos - writer.pos) as usize;
+        let record = Record::decode(&buf[start..start + cmd_pos.len as usize])?;
+        Ok(record.value)
+    }
+}
+
+/// Returns where `key` is as of the writes planned so far, `None` if it does
+/// not exist, and keeps it in `pending`. Expired keys no longer exist.
+fn current_pos(
+    pending: &mut HashMap<Vec<u8>, Option<CommandPos>>,
+    kv_index: &KeyDir,
+    key: &[u8],
+    now: u64,
+) -> Result<Option<CommandPos>> {
+    if let Some(cmd_pos) = pending.get(key) {
+        return Ok(*cmd_pos);
+    }
+    let cmd_pos = kv_index
+        .get(key)?
+        .filter(|cmd_pos| !is_expired(cmd_pos.expires_at, now));
+    pending.insert(key.to_vec(), cmd_pos);
+    Ok(cmd_pos)
+}
+
+/// Returns the previous location of a key the index was updated for. The
+/// update is made even if the lookup of the old location failed, whose
+/// record is then never counted as stale.
+fn known_pos(old_pos: Result<Option<CommandPos>>) -> Option<CommandPos> {
+    old_pos.unwrap_or_else(|err| {
+        warn!("lost track of a stale record: {}", err);
+        None
+    })
+}
+
+/// Appends `record` to the bytes `buf` that `writer` is about to write, and
+/// returns where it will be in the log. Nothing is appended if the record
+/// can't be encoded.
+fn append(buf: &mut Vec<u8>, writer: &LogWriter, record: &Record) -> io::Result<CommandPos> {
+    let encoded = record.encode()?;
+    let cmd_pos = CommandPos {
+        gen: writer.gen,
+        pos: writer.pos + buf.len() as u64,
+        len: encoded.len() as u64,
+        expires_at: record.expires_at,
+        version: record.version,
+    };
+    buf.extend_from_slice(&encoded);
+    Ok(cmd_pos)
+}
+
+/// Appends a batch marker, which always encodes as it is empty.
+fn append_marker(buf: &mut Vec<u8>, writer: &LogWriter, marker: &Record) -> CommandPos {
+    append(buf, writer, marker).expect("batch markers are empty")
+}
+
+/// Checks that the record of a write of a batch can be encoded.
+fn check_op_lengths(op: &WriteOp) -> io::Result<()> {
+    match op {
+        WriteOp::Set { key, value, .. } => check_lengths(key, value),
+        WriteOp::Remove { key } => check_lengths(key, &[]),
+        WriteOp::Conditional { key, value, .. } => {
+            check_lengths(key, value.as_deref().unwrap_or_default())
+        }
+        WriteOp::Batch { .. } => unreachable!("write batches are not nested"),
+    }
+}
+
+/// Hands the same error to every write of a failed batch.
+fn fail_all(tickets: impl Iterator<Item = u64>, err: KvsError) -> Vec<(u64, Result<()>)> {
+    let msg = err.to_string();
+    tickets
+        .map(|ticket| {
+            let err = io::Error::other(msg.clone());
+            (ticket, Err(KvsError::Io(err)))
+        })
+        .collect()
+}
@@ -0,0 +1,251 @@
+//! Hint files, written next to compacted logs so that opening a store doesn't
+//! have to read every value.
+//!
+//! A hint file starts with `HINT_MAGIC`, the length of the log it covers, the
+//! newest generation compacted into that log and the highest version written
+//! to the compacted logs, followed by one entry per set record of the log:
+//!
+//! ```text
+//! +------------+--------------+----------+----------+-----------------+--------------+-----+
+//! | crc32: u32 | key_len: u32 | pos: u64 | len: u64 | expires_at: u64 | version: u64 | key |
+//! +------------+--------------+----------+----------+-----------------+--------------+-----+
+//! ```
+//!
+//! Integers are little-endian. The checksum covers everything after itself.
+//! An expiry of 0 stands for a value that never expires.
+//!
+//! The highest version survives the removal records that compaction drops, so
+//! that versions are never given out twice.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Marks a hint file.
+const HINT_MAGIC: &[u8; 8] = b"KVSHINT\x01";
+
+/// Length of the header of a hint file, magic included.
+const HEADER_LEN: u64 = HINT_MAGIC.len() as u64 + 8 * 3;
+
+const ENTRY_HEADER_LEN: usize = 40;
+
+/// Location of a set record, as stored in a hint file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HintEntry {
+    pub key: Vec<u8>,
+    pub pos: u64,
+    pub len: u64,
+    pub expires_at: Option<u64>,
+    pub version: u64,
+}
+
+/// Header of a valid hint file. Its entries are read on demand, so that a
+/// hint never has to fit in memory.
+#[derive(Debug)]
+pub struct Hint {
+    /// Length of the log when the hint was written. Records after it are not
+    /// covered.
+    pub log_len: u64,
+    /// Newest generation compacted into the log. Logs up to it are obsolete
+    /// once the log exists.
+    pub compacted_gen: u64,
+    /// Highest version written to the logs compacted into the log.
+    pub max_version: u64,
+    path: PathBuf,
+}
+
+impl Hint {
+    /// Reads the entries of the hint file one by one.
+    pub fn entries(&self) -> io::Result<HintEntries> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(HEADER_LEN))?;
+        Ok(HintEntries { reader })
+    }
+}
+
+/// Iterator over the entries of a hint file.
+pub struct HintEntries {
+    reader: BufReader<File>,
+}
+
+impl Iterator for HintEntries {
+    type Item = io::Result<HintEntry>;
+
+    fn next(&mut self) -> Option<io::Result<HintEntry>> {
+        read_entry(&mut self.reader).transpose()
+    }
+}
+
+pub fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// Writes the hint file of a log as its entries come, to a temporary file
+/// that takes its final name once it is complete.
+pub struct HintWriter {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    compacted_gen: u64,
+    max_version: u64,
+    buf: Vec<u8>,
+}
+
+impl HintWriter {
+    pub fn create(
+        dir: &Path,
+        gen: u64,
+        compacted_gen: u64,
+        max_version: u64,
+    ) -> io::Result<HintWriter> {
+        let path = hint_path(dir, gen);
+        let tmp_path = path.with_extension("hint.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(HINT_MAGIC)?;
+        // The length of the log is only known once it is written.
+        writer.write_all(&0u64.to_le_bytes())?;
+        writer.write_all(&compacted_gen.to_le_bytes())?;
+        writer.write_all(&max_version.to_le_bytes())?;
+        Ok(HintWriter {
+            writer,
+            path,
+            tmp_path,
+            compacted_gen,
+            max_version,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, entry: &HintEntry) -> io::Result<()> {
+        let buf = &mut self.buf;
+        buf.clear();
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&entry.pos.to_le_bytes());
+        buf.extend_from_slice(&entry.len.to_le_bytes());
+        buf.extend_from_slice(&entry.expires_at.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&entry.version.to_le_bytes());
+        buf.extend_from_slice(&entry.key);
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        self.writer.write_all(buf)
+    }
+
+    /// Records the length of the log, syncs the hint file and gives it its
+    /// final name.
+    pub fn finish(self, log_len: u64) -> io::Result<Hint> {
+        let mut file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        file.seek(SeekFrom::Start(HINT_MAGIC.len() as u64))?;
+        file.write_all(&log_len.to_le_bytes())?;
+        file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(Hint {
+            log_len,
+            compacted_gen: self.compacted_gen,
+            max_version: self.max_version,
+            path: self.path,
+        })
+    }
+
+    /// Removes the temporary file of a hint that won't be finished.
+    pub fn discard(self) -> io::Result<()> {
+        drop(self.writer);
+        fs::remove_file(&self.tmp_path)
+    }
+}
+
+/// Reads the header of the hint file of `gen` and checks its entries.
+///
+/// Returns `None` if there is none, or if it is damaged and the log has to be
+/// replayed instead.
+pub fn read_hint(dir: &Path, gen: u64) -> io::Result<Option<Hint>> {
+    let path = hint_path(dir, gen);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    match parse_hint(path, BufReader::new(file)) {
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => Ok(None),
+        result => result,
+    }
+}
+
+fn parse_hint(path: PathBuf, mut reader: impl Read) -> io::Result<Option<Hint>> {
+    let mut magic = [0; 8];
+    if !read_full(&mut reader, &mut magic)? {
+        return Ok(None);
+    }
+    if &magic != HINT_MAGIC {
+        return Ok(None);
+    }
+    let mut fields = [0; 3];
+    for field in fields.iter_mut() {
+        let mut bytes = [0; 8];
+        if !read_full(&mut reader, &mut bytes)? {
+            return Ok(None);
+        }
+        *field = u64::from_le_bytes(bytes);
+    }
+
+    while read_entry(&mut reader)?.is_some() {}
+
+    Ok(Some(Hint {
+        log_len: fields[0],
+        compacted_gen: fields[1],
+        max_version: fields[2],
+        path,
+    }))
+}
+
+/// Reads the next entry of a hint file, `None` at the end of the file.
+/// A damaged or incomplete entry is an `InvalidData` error.
+fn read_entry(reader: &mut impl Read) -> io::Result<Option<HintEntry>> {
+    let mut header = [0; ENTRY_HEADER_LEN];
+    if !read_full(reader, &mut header)? {
+        return Ok(None);
+    }
+    let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let key_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+    // A damaged header may claim a huge key, so don't allocate it upfront.
+    let mut key = Vec::new();
+    reader.take(key_len).read_to_end(&mut key)?;
+    if (key.len() as u64) < key_len {
+        return Err(damaged());
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(&key);
+    if hasher.finalize() != crc {
+        return Err(damaged());
+    }
+
+    let field = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+    Ok(Some(HintEntry {
+        key,
+        pos: field(8),
+        len: field(16),
+        expires_at: Some(field(24)).filter(|&expires_at| expires_at != 0),
+        version: field(32),
+    }))
+}
+
+/// Fills `buf`, returning false if the reader is at its end. Ending in the
+/// middle of `buf` is an `InvalidData` error.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(damaged()),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+fn damaged() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "damaged hint entry")
+}
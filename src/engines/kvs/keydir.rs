@@ -0,0 +1,976 @@
+//! The index of a `KvStore`, which maps every key to the location of its
+//! latest record.
+//!
+//! By default the whole index is kept in memory. With a memory budget, the
+//! index lives in a key directory file sorted by key, of which only the first
+//! key of each block is kept in memory, along with the changes made since the
+//! file was written. Looking a key up costs at most the read of one block.
+//! Once the changes outgrow their share of the budget, they are frozen and
+//! merged into a new file in the background, while new changes pile up in
+//! their place.
+//!
+//! The key directory file starts with `KEYDIR_MAGIC`, followed by blocks of
+//! entries sorted by key:
+//!
+//! ```text
+//! +--------------+----------+----------+----------+-----------------+--------------+-----+
+//! | key_len: u32 | gen: u64 | pos: u64 | len: u64 | expires_at: u64 | version: u64 | key |
+//! +--------------+----------+----------+----------+-----------------+--------------+-----+
+//! ```
+//!
+//! Then comes a footer: the generation and offset in the logs up to which
+//! the file covers the writes, the highest version given out by then, a byte
+//! telling whether any value expires, the number of keys of the file, the
+//! number of logs followed by the generation, total and stale bytes of each,
+//! and the number of blocks followed by the offset, length, crc32 and first
+//! key of each. The file ends with the offset of the footer and a crc32 of
+//! the footer.
+//!
+//! Counts, block lengths, key lengths and crc32s are `u32`, everything else
+//! is `u64`. Integers are little-endian. An expiry of 0 stands for a value
+//! that never expires.
+//!
+//! The file is only trusted on open if every log it points into is still
+//! there, at least as long as it was, and no log it does not know of was
+//! created before the position it covers. The writes after that position are
+//! replayed from the logs. Otherwise the file is removed and the index is
+//! rebuilt from the logs, merging its changes into a new file as it goes.
+
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::warn;
+
+use super::compaction::LogStats;
+use super::record::FILE_MAGIC;
+use super::{log_path, read_exact_at, sync_dir, CommandPos, StoreCore};
+use crate::engines::scan::KeyRange;
+use crate::Result;
+
+/// Name of the key directory file of a bounded index.
+pub const KEYDIR_NAME: &str = "keydir";
+
+/// Marks a key directory file.
+const KEYDIR_MAGIC: &[u8; 8] = b"KVSKDIR\x01";
+
+/// Size from which a block of the key directory file is closed.
+const BLOCK_SIZE: usize = 4096;
+
+const ENTRY_HEADER_LEN: usize = 44;
+
+/// Length of the offset and checksum of the footer at the end of the file.
+const TRAILER_LEN: u64 = 12;
+
+/// Rough memory taken by an entry of the changes besides its key.
+const CHANGE_OVERHEAD: u64 = 96;
+
+/// Rough memory taken by a block of the file besides its first key.
+const BLOCK_OVERHEAD: u64 = 48;
+
+/// Changes are frozen once they reach this size at least, however small the
+/// budget, so that merges don't follow each other.
+const MIN_CHANGES_SIZE: u64 = 64 * 1024;
+
+/// Changes to the entries of a key directory file, `None` for removals.
+type Changes = BTreeMap<Vec<u8>, Option<CommandPos>>;
+
+/// Where the latest record of every key is.
+#[derive(Debug)]
+pub enum KeyDir {
+    /// The whole index in memory.
+    Memory(BTreeMap<Vec<u8>, CommandPos>),
+    /// The index in a key directory file, with recent changes in memory.
+    Bounded(Box<BoundedKeyDir>),
+}
+
+impl Default for KeyDir {
+    fn default() -> Self {
+        KeyDir::Memory(BTreeMap::new())
+    }
+}
+
+/// An index that keeps about `budget` bytes in memory.
+#[derive(Debug)]
+pub struct BoundedKeyDir {
+    dir: PathBuf,
+    budget: u64,
+    /// `None` until the first merge.
+    file: Option<Arc<KeyDirFile>>,
+    /// Changes being merged into a new file.
+    frozen: Option<Arc<FrozenChanges>>,
+    /// Changes since `frozen`.
+    changes: Changes,
+    changes_size: u64,
+    max_version: u64,
+    has_expiring: bool,
+    /// Number of keys, with the changes applied.
+    key_count: u64,
+}
+
+/// Changes frozen for a merge, with the state of the logs they bring the
+/// file up to.
+#[derive(Debug)]
+struct FrozenChanges {
+    changes: Changes,
+    coverage: Coverage,
+}
+
+/// What a key directory file holds the writes of.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// Generation of the log up to which the writes are in the file.
+    pub gen: u64,
+    /// Offset in the log of `gen` up to which the writes are in the file. If
+    /// it is 0, the log has to be loaded again entirely, as it was only
+    /// partly loaded from its hint file.
+    pub pos: u64,
+    /// Highest version given out by then.
+    pub max_version: u64,
+    /// Whether any value of the file expires.
+    pub has_expiring: bool,
+    /// Number of keys of the file.
+    pub keys: u64,
+    /// Generation, total and stale bytes of each log.
+    pub logs: Vec<(u64, u64, u64)>,
+}
+
+/// A key directory file, of which only the first key of each block is in
+/// memory.
+#[derive(Debug)]
+struct KeyDirFile {
+    file: File,
+    blocks: Vec<Block>,
+    coverage: Coverage,
+}
+
+#[derive(Debug)]
+struct Block {
+    offset: u64,
+    len: u32,
+    crc: u32,
+    first_key: Vec<u8>,
+}
+
+/// A merge of frozen changes into a new key directory file, which runs
+/// without the index locked.
+pub struct MergeJob {
+    dir: PathBuf,
+    file: Option<Arc<KeyDirFile>>,
+    frozen: Arc<FrozenChanges>,
+}
+
+/// A key directory file written by a merge, ready to be installed.
+pub struct MergedFile(KeyDirFile);
+
+/// Entries of a layer of the index, in key order or in reverse key order.
+type Layer<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Option<CommandPos>)>> + 'a>;
+
+impl KeyDir {
+    /// Opens a bounded index for the store in `dir`, made of the logs `gens`,
+    /// from its key directory file if it is still valid. Otherwise the file is
+    /// removed, and the index starts empty.
+    pub fn open_bounded(dir: &Path, budget: u64, gens: &[u64]) -> Result<KeyDir> {
+        let path = dir.join(KEYDIR_NAME);
+        let file = match KeyDirFile::open(&path)? {
+            Some(file) if file.is_valid_for(dir, gens)? => Some(Arc::new(file)),
+            Some(_) => {
+                warn!("rebuilding the key directory, it does not match the logs");
+                fs::remove_file(&path)?;
+                None
+            }
+            None => {
+                remove_key_dir(dir)?;
+                None
+            }
+        };
+        let (max_version, has_expiring, key_count) = file.as_ref().map_or((0, false, 0), |file| {
+            let coverage = &file.coverage;
+            (coverage.max_version, coverage.has_expiring, coverage.keys)
+        });
+        Ok(KeyDir::Bounded(Box::new(BoundedKeyDir {
+            dir: dir.to_owned(),
+            budget,
+            file,
+            frozen: None,
+            changes: Changes::new(),
+            changes_size: 0,
+            max_version,
+            has_expiring,
+            key_count,
+        })))
+    }
+
+    /// What the key directory file the index was opened from holds, if any.
+    /// The logs up to there don't have to be loaded.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        match self {
+            KeyDir::Bounded(keydir) => keydir.file.as_ref().map(|file| &file.coverage),
+            KeyDir::Memory(_) => None,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        match self {
+            KeyDir::Memory(entries) => Ok(entries.get(key).copied()),
+            KeyDir::Bounded(keydir) => keydir.get(key),
+        }
+    }
+
+    /// Points `key` to `cmd_pos` and returns its previous location.
+    ///
+    /// A bounded index may have to read the previous location from its file.
+    /// If that fails, the error is returned but `key` is updated anyway.
+    pub fn insert(&mut self, key: Vec<u8>, cmd_pos: CommandPos) -> Result<Option<CommandPos>> {
+        match self {
+            KeyDir::Memory(entries) => Ok(entries.insert(key, cmd_pos)),
+            KeyDir::Bounded(keydir) => {
+                let old_pos = keydir.get(&key);
+                if matches!(old_pos, Ok(None)) {
+                    keydir.key_count += 1;
+                }
+                keydir.has_expiring |= cmd_pos.expires_at.is_some();
+                keydir.change(key, Some(cmd_pos));
+                old_pos
+            }
+        }
+    }
+
+    /// Removes `key` and returns its previous location, like `insert`.
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<CommandPos>> {
+        match self {
+            KeyDir::Memory(entries) => Ok(entries.remove(key)),
+            KeyDir::Bounded(keydir) => {
+                let old_pos = keydir.get(key);
+                if matches!(old_pos, Ok(Some(_))) {
+                    keydir.key_count = keydir.key_count.saturating_sub(1);
+                }
+                if !matches!(old_pos, Ok(None)) {
+                    keydir.change(key.to_vec(), None);
+                }
+                old_pos
+            }
+        }
+    }
+
+    /// Returns at most `limit` entries whose key falls within `range`, from
+    /// the greatest key if `reverse` is set.
+    pub fn entries(
+        &self,
+        range: KeyRange,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, CommandPos)>> {
+        let entries = match self {
+            KeyDir::Memory(entries) => entries.range(range),
+            KeyDir::Bounded(keydir) => return keydir.entries(range, reverse, limit),
+        };
+        let entries = entries.map(|(key, cmd_pos)| (key.clone(), *cmd_pos));
+        Ok(match reverse {
+            true => entries.rev().take(limit).collect(),
+            false => entries.take(limit).collect(),
+        })
+    }
+
+    /// Number of keys in the index. A bounded index counts them as they are
+    /// added and removed, and may be off after failed reads of its file.
+    pub fn key_count(&self) -> u64 {
+        match self {
+            KeyDir::Memory(entries) => entries.len() as u64,
+            KeyDir::Bounded(keydir) => keydir.key_count,
+        }
+    }
+
+    /// Whether any value written so far expires.
+    pub fn has_expiring(&self) -> bool {
+        match self {
+            KeyDir::Memory(entries) => entries.values().any(|cmd_pos| cmd_pos.expires_at.is_some()),
+            KeyDir::Bounded(keydir) => keydir.has_expiring,
+        }
+    }
+
+    /// Accounts for a write of `version`, so that the versions given out
+    /// survive in the key directory file.
+    pub fn note_version(&mut self, version: u64) {
+        if let KeyDir::Bounded(keydir) = self {
+            keydir.max_version = keydir.max_version.max(version);
+        }
+    }
+
+    /// Whether the changes of a bounded index have outgrown their share of
+    /// the budget and no merge is pending.
+    pub fn needs_freeze(&self) -> bool {
+        match self {
+            KeyDir::Bounded(keydir) => {
+                keydir.frozen.is_none() && keydir.changes_size >= keydir.changes_budget()
+            }
+            KeyDir::Memory(_) => false,
+        }
+    }
+
+    /// Whether changes are waiting to be merged.
+    pub fn is_frozen(&self) -> bool {
+        matches!(self, KeyDir::Bounded(keydir) if keydir.frozen.is_some())
+    }
+
+    /// Sets the changes aside for a merge, as the writes of the logs up to
+    /// offset `pos` of the log of `gen`, whose byte counts are `stats`.
+    /// Does nothing if a merge is already pending.
+    pub fn freeze(&mut self, gen: u64, pos: u64, stats: &LogStats) {
+        if let KeyDir::Bounded(keydir) = self {
+            if keydir.frozen.is_some() {
+                return;
+            }
+            let coverage = Coverage {
+                gen,
+                pos,
+                max_version: keydir.max_version,
+                has_expiring: keydir.has_expiring,
+                keys: 0,
+                logs: stats.gen_stats(),
+            };
+            keydir.changes_size = 0;
+            keydir.frozen = Some(Arc::new(FrozenChanges {
+                changes: std::mem::take(&mut keydir.changes),
+                coverage,
+            }));
+        }
+    }
+
+    /// Returns the merge of the frozen changes, if there are any.
+    pub fn merge_job(&self) -> Option<MergeJob> {
+        match self {
+            KeyDir::Bounded(keydir) => keydir.frozen.as_ref().map(|frozen| MergeJob {
+                dir: keydir.dir.clone(),
+                file: keydir.file.clone(),
+                frozen: Arc::clone(frozen),
+            }),
+            KeyDir::Memory(_) => None,
+        }
+    }
+
+    /// Replaces the file and the frozen changes with the result of a merge.
+    pub fn install(&mut self, merged: MergedFile) {
+        if let KeyDir::Bounded(keydir) = self {
+            keydir.file = Some(Arc::new(merged.0));
+            keydir.frozen = None;
+        }
+    }
+
+    /// Freezes the changes and merges them right away, while the index is
+    /// being loaded.
+    pub fn merge_now(&mut self, gen: u64, pos: u64, stats: &LogStats) -> Result<()> {
+        self.freeze(gen, pos, stats);
+        if let Some(job) = self.merge_job() {
+            let merged = job.run()?;
+            self.install(merged);
+        }
+        Ok(())
+    }
+}
+
+impl BoundedKeyDir {
+    fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        if let Some(cmd_pos) = self.changes.get(key) {
+            return Ok(*cmd_pos);
+        }
+        if let Some(cmd_pos) = self
+            .frozen
+            .as_ref()
+            .and_then(|frozen| frozen.changes.get(key))
+        {
+            return Ok(*cmd_pos);
+        }
+        match &self.file {
+            Some(file) => file.get(key),
+            None => Ok(None),
+        }
+    }
+
+    fn change(&mut self, key: Vec<u8>, cmd_pos: Option<CommandPos>) {
+        match self.changes.entry(key) {
+            Entry::Occupied(mut entry) => {
+                entry.insert(cmd_pos);
+            }
+            Entry::Vacant(entry) => {
+                self.changes_size += entry.key().len() as u64 + CHANGE_OVERHEAD;
+                entry.insert(cmd_pos);
+            }
+        }
+    }
+
+    /// Size the changes can grow to before they are frozen. Frozen changes
+    /// and new ones share the budget with the blocks of the file.
+    fn changes_budget(&self) -> u64 {
+        let blocks_size = self.file.as_ref().map_or(0, |file| file.blocks_size());
+        (self.budget.saturating_sub(blocks_size) / 2).max(MIN_CHANGES_SIZE)
+    }
+
+    fn entries(
+        &self,
+        range: KeyRange,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, CommandPos)>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let mut layers: Vec<Layer> = vec![changes_layer(&self.changes, range.clone(), reverse)];
+        if let Some(frozen) = &self.frozen {
+            layers.push(changes_layer(&frozen.changes, range.clone(), reverse));
+        }
+        if let Some(file) = &self.file {
+            layers.push(Box::new(file.range(range, reverse).map(wrap_entry)));
+        }
+        let mut entries = Vec::new();
+        merge_layers(layers, reverse, |key, cmd_pos| {
+            entries.push((key, cmd_pos));
+            Ok(entries.len() < limit)
+        })?;
+        Ok(entries)
+    }
+}
+
+impl MergeJob {
+    /// Writes the entries of the file, with the frozen changes applied, to a
+    /// new key directory file, which then replaces the old one.
+    ///
+    /// The logs written since the previous merge are synced first, so that
+    /// the file never points to records a crash could still lose.
+    pub fn run(self) -> Result<MergedFile> {
+        let synced_gen = self.file.as_ref().map_or(0, |file| file.coverage.gen);
+        for &(gen, ..) in &self.frozen.coverage.logs {
+            if gen >= synced_gen {
+                sync_log(&self.dir, gen)?;
+            }
+        }
+
+        let path = self.dir.join(KEYDIR_NAME);
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = KeyDirWriter::create(&tmp_path)?;
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        let mut layers: Vec<Layer> = vec![changes_layer(&self.frozen.changes, all.clone(), false)];
+        if let Some(file) = &self.file {
+            layers.push(Box::new(file.range(all, false).map(wrap_entry)));
+        }
+        merge_layers(layers, false, |key, cmd_pos| {
+            writer.add(&key, cmd_pos)?;
+            Ok(true)
+        })?;
+        let (blocks, coverage) = writer.finish(self.frozen.coverage.clone())?;
+        fs::rename(&tmp_path, &path)?;
+        sync_dir(&self.dir)?;
+        Ok(MergedFile(KeyDirFile {
+            file: File::open(&path)?,
+            blocks,
+            coverage,
+        }))
+    }
+}
+
+impl KeyDirFile {
+    /// Opens the key directory file at `path` and reads its footer.
+    ///
+    /// Returns `None` if there is none, or if it is damaged.
+    fn open(path: &Path) -> Result<Option<KeyDirFile>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let len = file.metadata()?.len();
+        if len < KEYDIR_MAGIC.len() as u64 + TRAILER_LEN {
+            return Ok(None);
+        }
+        let mut magic = [0; 8];
+        read_exact_at(&file, &mut magic, 0)?;
+        let mut trailer = [0; TRAILER_LEN as usize];
+        read_exact_at(&file, &mut trailer, len - TRAILER_LEN)?;
+        let footer_offset = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+        let crc = u32::from_le_bytes(trailer[8..].try_into().unwrap());
+        if &magic != KEYDIR_MAGIC
+            || footer_offset < KEYDIR_MAGIC.len() as u64
+            || footer_offset > len - TRAILER_LEN
+        {
+            return Ok(None);
+        }
+        let mut footer = vec![0; (len - TRAILER_LEN - footer_offset) as usize];
+        read_exact_at(&file, &mut footer, footer_offset)?;
+        if crc32fast::hash(&footer) != crc {
+            return Ok(None);
+        }
+        Ok(decode_footer(&footer).map(|(coverage, blocks)| KeyDirFile {
+            file,
+            blocks,
+            coverage,
+        }))
+    }
+
+    /// Whether the logs `gens` of `dir` still hold the records the file
+    /// points to, and nothing it does not know of.
+    fn is_valid_for(&self, dir: &Path, gens: &[u64]) -> Result<bool> {
+        let coverage = &self.coverage;
+        for &(gen, total, stale) in &coverage.logs {
+            let len = match fs::metadata(log_path(dir, gen)) {
+                Ok(metadata) => metadata.len(),
+                Err(err) if err.kind() == io::ErrorKind::NotFound && total == stale => continue,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(err) => return Err(err.into()),
+            };
+            if len < FILE_MAGIC.len() as u64 + total {
+                return Ok(false);
+            }
+        }
+        for &gen in gens.iter().filter(|&&gen| gen < coverage.gen) {
+            let known = coverage.logs.iter().any(|&(log_gen, ..)| log_gen == gen);
+            if !known && fs::metadata(log_path(dir, gen))?.len() > FILE_MAGIC.len() as u64 {
+                return Ok(false);
+            }
+        }
+        if coverage.pos > 0 {
+            match fs::metadata(log_path(dir, coverage.gen)) {
+                Ok(metadata) if metadata.len() >= coverage.pos => {}
+                Ok(_) => return Ok(false),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Memory taken by the blocks.
+    fn blocks_size(&self) -> u64 {
+        self.blocks
+            .iter()
+            .map(|block| block.first_key.len() as u64 + BLOCK_OVERHEAD)
+            .sum()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        let index = self
+            .blocks
+            .partition_point(|block| block.first_key.as_slice() <= key);
+        if index == 0 {
+            return Ok(None);
+        }
+        for (entry_key, cmd_pos) in self.read_block(index - 1)? {
+            if entry_key.as_slice() == key {
+                return Ok(Some(cmd_pos));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads and decodes the entries of the block at `index`.
+    fn read_block(&self, index: usize) -> Result<Vec<(Vec<u8>, CommandPos)>> {
+        let block = &self.blocks[index];
+        let mut bytes = vec![0; block.len as usize];
+        read_exact_at(&self.file, &mut bytes, block.offset)?;
+        if crc32fast::hash(&bytes) != block.crc {
+            return Err(damaged_block(block.offset).into());
+        }
+        decode_entries(&bytes).ok_or_else(|| damaged_block(block.offset).into())
+    }
+
+    /// Iterates over the entries whose key falls within `range`, a block at a
+    /// time.
+    fn range(&self, range: KeyRange, reverse: bool) -> FileRange<'_> {
+        let next_block = match (reverse, &range) {
+            (false, (Bound::Included(start) | Bound::Excluded(start), _)) => self
+                .blocks
+                .partition_point(|block| block.first_key <= *start)
+                .saturating_sub(1),
+            (false, (Bound::Unbounded, _)) => 0,
+            (true, (_, Bound::Included(end))) => {
+                self.blocks.partition_point(|block| block.first_key <= *end)
+            }
+            (true, (_, Bound::Excluded(end))) => {
+                self.blocks.partition_point(|block| block.first_key < *end)
+            }
+            (true, (_, Bound::Unbounded)) => self.blocks.len(),
+        };
+        FileRange {
+            file: self,
+            range,
+            reverse,
+            next_block,
+            entries: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+}
+
+/// Iterator over the entries of a key directory file within a range.
+struct FileRange<'a> {
+    file: &'a KeyDirFile,
+    range: KeyRange,
+    reverse: bool,
+    /// Block to read next, counted from 1 in reverse.
+    next_block: usize,
+    entries: std::vec::IntoIter<(Vec<u8>, CommandPos)>,
+    done: bool,
+}
+
+impl Iterator for FileRange<'_> {
+    type Item = Result<(Vec<u8>, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some((key, cmd_pos)) = self.entries.next() {
+                let (start, end) = (&self.range.0, &self.range.1);
+                let before_start = match start {
+                    Bound::Included(start) => key < *start,
+                    Bound::Excluded(start) => key <= *start,
+                    Bound::Unbounded => false,
+                };
+                let after_end = match end {
+                    Bound::Included(end) => key > *end,
+                    Bound::Excluded(end) => key >= *end,
+                    Bound::Unbounded => false,
+                };
+                match self.reverse {
+                    false if after_end => self.done = true,
+                    true if before_start => self.done = true,
+                    _ if before_start || after_end => {}
+                    _ => return Some(Ok((key, cmd_pos))),
+                }
+                continue;
+            }
+
+            let index = match self.reverse {
+                false if self.next_block < self.file.blocks.len() => self.next_block,
+                true if self.next_block > 0 => self.next_block - 1,
+                _ => return None,
+            };
+            let mut entries = match self.file.read_block(index) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            };
+            if self.reverse {
+                entries.reverse();
+                self.next_block -= 1;
+            } else {
+                self.next_block += 1;
+            }
+            self.entries = entries.into_iter();
+        }
+        None
+    }
+}
+
+/// Writes a key directory file, entries in key order first.
+struct KeyDirWriter {
+    writer: BufWriter<File>,
+    pos: u64,
+    blocks: Vec<Block>,
+    /// Entries of the block being filled.
+    block: Vec<u8>,
+    first_key: Vec<u8>,
+    keys: u64,
+}
+
+impl KeyDirWriter {
+    fn create(path: &Path) -> Result<KeyDirWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(KEYDIR_MAGIC)?;
+        Ok(KeyDirWriter {
+            writer,
+            pos: KEYDIR_MAGIC.len() as u64,
+            blocks: Vec::new(),
+            block: Vec::with_capacity(BLOCK_SIZE * 2),
+            first_key: Vec::new(),
+            keys: 0,
+        })
+    }
+
+    fn add(&mut self, key: &[u8], cmd_pos: CommandPos) -> io::Result<()> {
+        if self.block.is_empty() {
+            self.first_key = key.to_vec();
+        }
+        let block = &mut self.block;
+        block.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        block.extend_from_slice(&cmd_pos.gen.to_le_bytes());
+        block.extend_from_slice(&cmd_pos.pos.to_le_bytes());
+        block.extend_from_slice(&cmd_pos.len.to_le_bytes());
+        block.extend_from_slice(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes());
+        block.extend_from_slice(&cmd_pos.version.to_le_bytes());
+        block.extend_from_slice(key);
+        self.keys += 1;
+        if block.len() >= BLOCK_SIZE {
+            self.close_block()?;
+        }
+        Ok(())
+    }
+
+    fn close_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.blocks.push(Block {
+            offset: self.pos,
+            len: self.block.len() as u32,
+            crc: crc32fast::hash(&self.block),
+            first_key: std::mem::take(&mut self.first_key),
+        });
+        self.pos += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the footer and syncs the file.
+    fn finish(mut self, mut coverage: Coverage) -> io::Result<(Vec<Block>, Coverage)> {
+        self.close_block()?;
+        coverage.keys = self.keys;
+        let footer = encode_footer(&coverage, &self.blocks);
+        self.writer.write_all(&footer)?;
+        self.writer.write_all(&self.pos.to_le_bytes())?;
+        self.writer
+            .write_all(&crc32fast::hash(&footer).to_le_bytes())?;
+        let file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok((self.blocks, coverage))
+    }
+}
+
+impl StoreCore {
+    /// Merges the frozen changes of a bounded index into a new key directory
+    /// file. With `force`, the changes made so far are frozen and merged too,
+    /// so that the file covers every write up to now.
+    pub(super) fn merge_key_dir(&self, mut force: bool) -> Result<()> {
+        if self.options.index_memory_budget.is_none() {
+            return Ok(());
+        }
+        loop {
+            let job = {
+                // Mutex: LogWriter, so that the index holds every write up to
+                // its position.
+                let writer = match force {
+                    true => Some(self.writer().lock().unwrap()),
+                    false => None,
+                };
+                // RwLock: kv_index
+                let mut kv_index = self.kv_index.write().unwrap();
+                if let Some(writer) = &writer {
+                    if !kv_index.is_frozen() {
+                        let stats = self.stats.lock().unwrap();
+                        kv_index.freeze(writer.gen, writer.pos, &stats);
+                        force = false;
+                    }
+                }
+                kv_index.merge_job()
+            };
+            if let Some(job) = job {
+                let merged = job.run()?;
+                self.kv_index.write().unwrap().install(merged);
+            }
+            if !force {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Removes the key directory file of `dir`, which stops matching the logs
+/// once the store is written to without it.
+pub fn remove_key_dir(dir: &Path) -> Result<()> {
+    for name in [KEYDIR_NAME, "keydir.tmp"] {
+        match fs::remove_file(dir.join(name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn sync_log(dir: &Path, gen: u64) -> io::Result<()> {
+    match OpenOptions::new().write(true).open(log_path(dir, gen)) {
+        Ok(file) => file.sync_data(),
+        // Compacted away, its records are in another log by now.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn changes_layer(changes: &Changes, range: KeyRange, reverse: bool) -> Layer<'_> {
+    let entries = changes
+        .range(range)
+        .map(|(key, cmd_pos)| Ok((key.clone(), *cmd_pos)));
+    match reverse {
+        true => Box::new(entries.rev()),
+        false => Box::new(entries),
+    }
+}
+
+fn wrap_entry(entry: Result<(Vec<u8>, CommandPos)>) -> Result<(Vec<u8>, Option<CommandPos>)> {
+    entry.map(|(key, cmd_pos)| (key, Some(cmd_pos)))
+}
+
+/// Hands the entries of `layers` to `f` in key order, or in reverse key
+/// order, until it returns false. The first layers take precedence over the
+/// others for the keys they have, and removals hide those keys.
+fn merge_layers(
+    layers: Vec<Layer>,
+    reverse: bool,
+    mut f: impl FnMut(Vec<u8>, CommandPos) -> Result<bool>,
+) -> Result<()> {
+    let mut layers: Vec<_> = layers.into_iter().map(Iterator::peekable).collect();
+    loop {
+        let mut next: Option<Vec<u8>> = None;
+        for layer in &mut layers {
+            match layer.peek() {
+                Some(Ok((key, _))) => {
+                    let comes_first = next.as_ref().is_none_or(|next| match reverse {
+                        true => key > next,
+                        false => key < next,
+                    });
+                    if comes_first {
+                        next = Some(key.clone());
+                    }
+                }
+                Some(Err(_)) => return layer.next().unwrap().map(|_| ()),
+                None => {}
+            }
+        }
+        let next = match next {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+
+        let mut found = None;
+        for layer in &mut layers {
+            if matches!(layer.peek(), Some(Ok((key, _))) if *key == next) {
+                let entry = layer.next().unwrap()?;
+                found.get_or_insert(entry);
+            }
+        }
+        if let Some((key, Some(cmd_pos))) = found {
+            if !f(key, cmd_pos)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn decode_entries(mut bytes: &[u8]) -> Option<Vec<(Vec<u8>, CommandPos)>> {
+    let mut entries = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < ENTRY_HEADER_LEN {
+            return None;
+        }
+        let key_len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+        let field =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let cmd_pos = CommandPos {
+            gen: field(4),
+            pos: field(12),
+            len: field(20),
+            expires_at: Some(field(28)).filter(|&expires_at| expires_at != 0),
+            version: field(36),
+        };
+        let end = ENTRY_HEADER_LEN.checked_add(key_len)?;
+        let key = bytes.get(ENTRY_HEADER_LEN..end)?.to_vec();
+        entries.push((key, cmd_pos));
+        bytes = &bytes[end..];
+    }
+    Some(entries)
+}
+
+fn encode_footer(coverage: &Coverage, blocks: &[Block]) -> Vec<u8> {
+    let mut footer = Vec::new();
+    footer.extend_from_slice(&coverage.gen.to_le_bytes());
+    footer.extend_from_slice(&coverage.pos.to_le_bytes());
+    footer.extend_from_slice(&coverage.max_version.to_le_bytes());
+    footer.push(coverage.has_expiring as u8);
+    footer.extend_from_slice(&coverage.keys.to_le_bytes());
+    footer.extend_from_slice(&(coverage.logs.len() as u32).to_le_bytes());
+    for &(gen, total, stale) in &coverage.logs {
+        footer.extend_from_slice(&gen.to_le_bytes());
+        footer.extend_from_slice(&total.to_le_bytes());
+        footer.extend_from_slice(&stale.to_le_bytes());
+    }
+    footer.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+    for block in blocks {
+        footer.extend_from_slice(&block.offset.to_le_bytes());
+        footer.extend_from_slice(&block.len.to_le_bytes());
+        footer.extend_from_slice(&block.crc.to_le_bytes());
+        footer.extend_from_slice(&(block.first_key.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&block.first_key);
+    }
+    footer
+}
+
+fn decode_footer(mut bytes: &[u8]) -> Option<(Coverage, Vec<Block>)> {
+    let gen = take_u64(&mut bytes)?;
+    let pos = take_u64(&mut bytes)?;
+    let max_version = take_u64(&mut bytes)?;
+    let has_expiring = take(&mut bytes, 1)?[0] != 0;
+    let keys = take_u64(&mut bytes)?;
+    let log_count = take_u32(&mut bytes)?;
+    let mut logs = Vec::new();
+    for _ in 0..log_count {
+        logs.push((
+            take_u64(&mut bytes)?,
+            take_u64(&mut bytes)?,
+            take_u64(&mut bytes)?,
+        ));
+    }
+    let block_count = take_u32(&mut bytes)?;
+    let mut blocks = Vec::new();
+    for _ in 0..block_count {
+        let offset = take_u64(&mut bytes)?;
+        let len = take_u32(&mut bytes)?;
+        let crc = take_u32(&mut bytes)?;
+        let key_len = take_u32(&mut bytes)?;
+        let first_key = take(&mut bytes, key_len as usize)?.to_vec();
+        blocks.push(Block {
+            offset,
+            len,
+            crc,
+            first_key,
+        });
+    }
+    if !bytes.is_empty() {
+        return None;
+    }
+    let coverage = Coverage {
+        gen,
+        pos,
+        max_version,
+        has_expiring,
+        keys,
+        logs,
+    };
+    Some((coverage, blocks))
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if bytes.len() < len {
+        return None;
+    }
+    let (head, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Some(head)
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    take(bytes, 8).map(|head| u64::from_le_bytes(head.try_into().unwrap()))
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Option<u32> {
+    take(bytes, 4).map(|head| u32::from_le_bytes(head.try_into().unwrap()))
+}
+
+fn damaged_block(offset: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("damaged block at offset {} of the key directory", offset),
+    )
+}
@@ -0,0 +1,1581 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use std::vec;
+
+use log::{error, warn};
+use memmap2::Mmap;
+use serde_json::Deserializer;
+
+use self::cache::ValueCache;
+use self::compaction::{recover_compactions, Compactor, LogStats};
+use self::flusher::Flusher;
+use self::group_commit::{CommitQueue, WriteOp};
+use self::hint::Hint;
+use self::keydir::{remove_key_dir, KeyDir};
+use self::record::{Record, RecordType, FILE_MAGIC};
+use self::snapshot::Snapshots;
+use super::batch::BatchOp;
+use super::checkpoint::create_checkpoint;
+use super::condition::Condition;
+use super::expiry::{expiry_after, is_expired, now_millis, time_left};
+use super::lock::DirLock;
+use super::scan::{is_empty_range, key_range, KeyRange};
+use super::stats::OpCounters;
+use crate::util::Command;
+use crate::{
+    EngineStats, KvsEngine, KvsError, Result, ScanIter, ScanOptions, SyncPolicy, Versioned,
+    WriteBatch,
+};
+
+pub use self::cache::CacheStats;
+pub use self::options::KvStoreOptions;
+pub use self::snapshot::KvStoreSnapshot;
+pub use self::verify::{Problem, VerifyReport};
+
+mod cache;
+mod checkpoint;
+mod compaction;
+mod flusher;
+mod group_commit;
+mod hint;
+mod keydir;
+mod options;
+mod record;
+mod snapshot;
+mod verify;
+
+/// Name of the single log file written by older versions of `KvStore`.
+const LEGACY_LOG_NAME: &str = "log.json";
+
+/// Number of index entries handled at a time by the sweeps and compactions
+/// that go through the whole index.
+const INDEX_CHUNK: usize = 1024;
+
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
+///
+/// Key/value pairs are persisted to disk in log files. Log files are named after
+/// monotonically increasing generation numbers with a `log` extension name.
+/// Each record carries its key and value lengths and a CRC32 checksum.
+/// A `BTreeMap` in memory stores the keys and the value locations, for fast
+/// queries and ordered scans. With `KvStoreOptions::index_memory_budget`, the
+/// index is kept in a sorted key directory file instead, of which only a
+/// sparse part and the recent changes stay in memory.
+///
+/// Reads only take a shared lock on the index and use positioned reads on
+/// shared file handles, or memory maps of the sealed logs if the options say
+/// so, so they run in parallel with each other and with the single writer.
+/// An optional cache keeps the values of the most recently read
+/// keys in memory.
+///
+/// Concurrent writes are committed in groups: one writer appends the records
+/// of all the writers waiting behind it at once and syncs them together.
+///
+/// While snapshots are alive, writes keep the index entries they replace, so
+/// that each snapshot reads the values as of its version. Compaction leaves
+/// the logs holding those values in place until the snapshots are dropped.
+///
+/// Stale bytes are tracked per log file. Once they reach the configured
+/// garbage ratio or threshold, a background thread compacts the oldest logs.
+/// Compacted logs get a hint file listing their keys and value locations,
+/// which lets `open` rebuild the index without reading the values.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let mut store = KvStore::open(current_dir()?)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    core: Arc<StoreCore>,
+    /// `None` if the store is read-only.
+    compactor: Option<Arc<Compactor>>,
+    /// Only held so that the thread stops with the last clone.
+    _flusher: Option<Arc<Flusher>>,
+}
+
+/// State shared by all clones of a `KvStore` and its compaction thread.
+#[derive(Debug)]
+struct StoreCore {
+    path: PathBuf,
+    options: KvStoreOptions,
+    kv_index: RwLock<KeyDir>,
+    /// `None` unless values are cached.
+    cache: Option<ValueCache>,
+    /// `None` if the store is read-only.
+    writer: Option<Mutex<LogWriter>>,
+    readers: LogReaders,
+    stats: Mutex<LogStats>,
+    ops: OpCounters,
+    commit_queue: CommitQueue,
+    /// Held by compactions and checkpoints, which can't run together.
+    compaction_lock: Mutex<()>,
+    /// Whether any value was written with an expiry. Sweeps are skipped
+    /// otherwise.
+    has_expiring: AtomicBool,
+    /// Highest version given to a write so far. Only changed with the index
+    /// locked, by the leader of a commit or by the refresh of a read-only
+    /// store.
+    last_version: AtomicU64,
+    /// Live snapshots and the older entries they need.
+    snapshots: Mutex<Snapshots>,
+    /// End of the records loaded from each log, from which a read-only store
+    /// picks up new appends.
+    scanned: Mutex<BTreeMap<u64, u64>>,
+    /// Held until the last user of the store is gone. `None` if the store is
+    /// read-only.
+    _lock: Option<DirLock>,
+}
+
+/// Location of a serialized command in the log files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    /// When the value expires, in milliseconds since the Unix epoch.
+    expires_at: Option<u64>,
+    /// Version of the write, 0 if it was written without one.
+    version: u64,
+}
+
+/// The active log file that takes all new writes.
+#[derive(Debug)]
+struct LogWriter {
+    gen: u64,
+    pos: u64,
+    file: File,
+    /// Bytes written since the file was last synced.
+    unsynced: u64,
+    /// Set when a failed write could not be undone, leaving bytes that are
+    /// not a whole record at the end of the log. No more writes are made.
+    poisoned: bool,
+}
+
+/// Result of the replay of a log file.
+#[derive(Debug, Clone, Copy)]
+struct Replayed {
+    /// Where the valid records end.
+    end: u64,
+    /// Whether damaged or incomplete data follows.
+    damaged: bool,
+    /// Highest version of the records replayed.
+    max_version: u64,
+}
+
+/// State rebuilt from the log files.
+#[derive(Debug, Default)]
+struct LoadedLogs {
+    kv_index: KeyDir,
+    stats: LogStats,
+    scanned: BTreeMap<u64, u64>,
+    max_version: u64,
+}
+
+/// Read-only handles to the log files.
+#[derive(Debug)]
+struct LogReaders {
+    files: RwLock<HashMap<u64, Arc<File>>>,
+    /// Maps of the sealed logs, `None` unless reads go through memory maps.
+    maps: Option<RwLock<HashMap<u64, Arc<Mmap>>>>,
+    /// Logs from this generation on may still be appended to, and are read
+    /// through their file handles.
+    sealed_below: AtomicU64,
+    /// Generations below it have been compacted away and must not be reopened.
+    safe_gen: AtomicU64,
+}
+
+/// Entries of a chunk of a scan, and the last key the chunk covers, `None`
+/// if the scan is over.
+type Chunk = (Vec<(Vec<u8>, CommandPos)>, Option<Vec<u8>>);
+
+/// Iterator over the pairs of a scan, which takes the index entries a chunk
+/// at a time and reads their values as it advances.
+struct ChunkedScan<F, R> {
+    /// Takes the entries of the next chunk of at most the given size within
+    /// the range, in scan order.
+    next_chunk: F,
+    /// Reads the value at an entry, `None` if it is gone.
+    read_value: R,
+    /// The part of the range that no chunk covered yet.
+    range: KeyRange,
+    reverse: bool,
+    remaining: Option<usize>,
+    chunk: vec::IntoIter<(Vec<u8>, CommandPos)>,
+    done: bool,
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+
+    /// Set the value of a key to the given bytes.
+    /// Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let core = &self.core;
+        if core.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let op = WriteOp::Set {
+            key,
+            value,
+            expires_at: None,
+        };
+        core.commit_queue.submit(core, op)?;
+        self.try_compact_log();
+        Ok(())
+    }
+
+    /// Get the value of a key as bytes.
+    /// If the key does not exist, return None.
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.core.ops.read();
+        let pos = self.core.kv_index.read().unwrap().get(&key)?;
+        match pos {
+            Some(pos) => self.core.read_value(&key, pos),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a given key.
+    /// Return an error if the key does not exist or is not removed successfully.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let core = &self.core;
+        if core.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        core.commit_queue.submit(core, WriteOp::Remove { key })?;
+        self.try_compact_log();
+        Ok(())
+    }
+
+    /// Writes the expiry along with the value. Expired keys are dropped from
+    /// the index when they are next looked up, and from the logs when they
+    /// are compacted.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let core = &self.core;
+        if core.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let op = WriteOp::Set {
+            key,
+            value,
+            expires_at: Some(expiry_after(ttl)),
+        };
+        core.commit_queue.submit(core, op)?;
+        self.try_compact_log();
+        Ok(())
+    }
+
+    /// Appends the records of the batch between a begin and a commit marker,
+    /// in a single write, and applies them to the index together.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.apply_batch_if(batch, Vec::new())
+    }
+
+    /// Checks the versions in the index while the batch is committed, before
+    /// any of its records is appended.
+    fn apply_batch_if(
+        &self,
+        batch: WriteBatch,
+        expected: Vec<(Vec<u8>, Option<u64>)>,
+    ) -> Result<()> {
+        let core = &self.core;
+        if core.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if batch.is_empty() && expected.is_empty() {
+            return Ok(());
+        }
+        let ops = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value, ttl_ms } => WriteOp::Set {
+                    key,
+                    value,
+                    expires_at: ttl_ms.map(|ttl_ms| expiry_after(Duration::from_millis(ttl_ms))),
+                },
+                BatchOp::Rm { key } => WriteOp::Remove { key },
+            })
+            .collect();
+        let op = WriteOp::Batch {
+            checks: expected,
+            ops,
+        };
+        core.commit_queue.submit(core, op)?;
+        self.try_compact_log();
+        Ok(())
+    }
+
+    /// Checks the current value while the write is committed, with the index
+    /// locked, so that no other write comes in between.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.write_if(key, Condition::Equals(expected), new)
+    }
+
+    /// Checks that the key exists while the write is committed.
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_if(key, Condition::Present, Some(value))
+    }
+
+    /// Reads the version and the time of modification from the record of the
+    /// value.
+    fn get_with_meta_bytes(&self, key: Vec<u8>) -> Result<Option<Versioned<Vec<u8>>>> {
+        self.core.ops.read();
+        let pos = self.core.kv_index.read().unwrap().get(&key)?;
+        let record = match pos {
+            Some(pos) => self.core.read_set(&key, pos)?,
+            None => None,
+        };
+        Ok(record.map(|record| Versioned::new(record.value, record.version, record.modified_at)))
+    }
+
+    /// Checks the version in the index while the write is committed.
+    fn set_bytes_if_version(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<()> {
+        self.write_if(key, Condition::Version(version), Some(value))
+    }
+
+    /// Checks the version in the index while the removal is committed.
+    fn remove_bytes_if_version(&self, key: Vec<u8>, version: u64) -> Result<()> {
+        self.write_if(key, Condition::Version(version), None)
+    }
+
+    /// Get the time left before a key expires, from the index.
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.core.ops.read();
+        let pos = self.core.kv_index.read().unwrap().get(&key)?;
+        match pos {
+            Some(pos) if is_expired(pos.expires_at, now_millis()) => {
+                self.core.expire(&key, pos)?;
+                Err(KvsError::KeyNotFound)
+            }
+            Some(pos) => Ok(pos.expires_at.map(time_left)),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Takes the matching keys from the index a chunk at a time, resuming
+    /// after the last key of the previous chunk, and reads their values as
+    /// the iterator advances. Keys removed in the meantime are skipped.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<ScanIter> {
+        self.core.ops.read();
+        let reverse = options.reverse;
+        let chunk_core = Arc::clone(&self.core);
+        let core = Arc::clone(&self.core);
+        Ok(Box::new(ChunkedScan::new(
+            key_range(range),
+            options,
+            move |range, size| {
+                let entries = chunk_core
+                    .kv_index
+                    .read()
+                    .unwrap()
+                    .entries(range, reverse, size)?;
+                let last_key = match entries.len() == size {
+                    true => entries.last().map(|(key, _)| key.clone()),
+                    false => None,
+                };
+                Ok((entries, last_key))
+            },
+            move |key: &[u8], cmd_pos| core.read_value(key, cmd_pos),
+        )))
+    }
+
+    /// Hard-links the sealed logs and their hint files into `dest_dir`,
+    /// after sealing the active log. Compactions wait for it to finish.
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        create_checkpoint(dest_dir, |dest_dir| self.core.checkpoint(dest_dir))
+    }
+
+    /// Registers a snapshot, for which writes keep the entries they replace
+    /// until it is dropped.
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        Ok(KvStoreSnapshot::take(&self.core))
+    }
+
+    /// Takes the key count from the index and the byte counts from the
+    /// stats of the logs, which leave out the file headers.
+    fn stats(&self) -> Result<EngineStats> {
+        let core = &self.core;
+        let keys = core.kv_index.read().unwrap().key_count();
+        let stats = core.stats.lock().unwrap();
+        let (compactions, last_compaction) = stats.compactions();
+        Ok(EngineStats {
+            keys,
+            total_bytes: stats.total(),
+            stale_bytes: stats.stale(),
+            compactions,
+            last_compaction,
+            reads: core.ops.reads(),
+            writes: core.ops.writes(),
+        })
+    }
+}
+
+impl KvStore {
+    /// Opens a `KvStore` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// The directory is locked against other processes until the store and
+    /// all its clones are dropped.
+    ///
+    /// Logs written by older versions in JSON, including a single `log.json`,
+    /// are converted to the binary record format first. Files left by a
+    /// compaction interrupted by a crash are cleaned up.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    /// A damaged record is reported as `KvsError::CorruptedLog`, and a
+    /// directory locked by another process as `KvsError::Locked`.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = path.into();
+        if options.read_only {
+            return KvStore::open_read_only_with(path, options);
+        }
+        fs::create_dir_all(&path)?;
+        let lock = DirLock::acquire(&path)?;
+        migrate_json_logs(&path)?;
+        let (gens, hints) = recover_compactions(&path, false)?;
+        let kv_index = match options.index_memory_budget {
+            Some(budget) => KeyDir::open_bounded(&path, budget, &gens)?,
+            None => {
+                // It would not match the logs once they are written to.
+                remove_key_dir(&path)?;
+                KeyDir::default()
+            }
+        };
+        let loaded = load_logs(&path, &gens, hints, &options, kv_index)?;
+        let has_expiring = loaded.kv_index.has_expiring();
+
+        // Keep appending to the newest generation unless it is already full.
+        let gen = match gens.last() {
+            Some(&gen) if fs::metadata(log_path(&path, gen))?.len() < options.segment_size => gen,
+            Some(&gen) => gen + 1,
+            None => 1,
+        };
+        let writer = LogWriter::open(&path, gen)?;
+        let readers = LogReaders::new(options.mmap_reads);
+        readers.seal_below(gen);
+
+        let core = Arc::new(StoreCore {
+            path,
+            cache: options.value_cache_size.map(ValueCache::new),
+            options,
+            kv_index: RwLock::new(loaded.kv_index),
+            writer: Some(Mutex::new(writer)),
+            readers,
+            stats: Mutex::new(loaded.stats),
+            ops: OpCounters::default(),
+            commit_queue: CommitQueue::default(),
+            compaction_lock: Mutex::new(()),
+            has_expiring: AtomicBool::new(has_expiring),
+            last_version: AtomicU64::new(loaded.max_version),
+            snapshots: Mutex::new(Snapshots::default()),
+            scanned: Mutex::new(loaded.scanned),
+            _lock: Some(lock),
+        });
+        let flusher = match core.options.sync_policy {
+            SyncPolicy::EveryMillis(millis) => Some(Arc::new(Flusher::spawn(
+                Arc::clone(&core),
+                Duration::from_millis(millis),
+            )?)),
+            _ => None,
+        };
+        let kv_store = KvStore {
+            compactor: Some(Arc::new(Compactor::spawn(Arc::clone(&core))?)),
+            _flusher: flusher,
+            core,
+        };
+
+        kv_store.try_compact_log();
+
+        Ok(kv_store)
+    }
+
+    /// Opens a `KvStore` with the given path for reading only.
+    ///
+    /// The directory is not locked, so another process can have it open for
+    /// writing, and nothing in it is written, created or removed. Writes fail
+    /// with `KvsError::ReadOnly`. The store sees the data as of the last
+    /// `refresh`, or as of the open.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    /// Logs in the JSON format of older versions can't be read this way and
+    /// give an error.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default().read_only(true))
+    }
+
+    /// Opens the store without the lock. Nothing in the directory is written,
+    /// created or removed, and there is no compaction.
+    fn open_read_only_with(path: PathBuf, options: KvStoreOptions) -> Result<KvStore> {
+        if has_json_logs(&path)? {
+            return Err(KvsError::StringError(format!(
+                "the logs in {} need to be converted by opening the store for writing",
+                path.display()
+            )));
+        }
+
+        let core = Arc::new(StoreCore {
+            path,
+            cache: options.value_cache_size.map(ValueCache::new),
+            readers: LogReaders::new(options.mmap_reads),
+            options,
+            kv_index: RwLock::new(KeyDir::default()),
+            writer: None,
+            stats: Mutex::new(LogStats::default()),
+            ops: OpCounters::default(),
+            commit_queue: CommitQueue::default(),
+            compaction_lock: Mutex::new(()),
+            has_expiring: AtomicBool::new(false),
+            last_version: AtomicU64::new(0),
+            snapshots: Mutex::new(Snapshots::default()),
+            scanned: Mutex::new(BTreeMap::new()),
+            _lock: None,
+        });
+        retry_vanished(|| core.refresh())?;
+        Ok(KvStore {
+            core,
+            compactor: None,
+            _flusher: None,
+        })
+    }
+
+    /// Picks up the writes made since the store was opened or last refreshed,
+    /// by the process that has the directory open for writing.
+    ///
+    /// Only read-only stores need it: it does nothing for a store open for
+    /// writing. New appends are replayed from where the last refresh stopped.
+    /// Once a compaction has replaced logs, the index is rebuilt instead.
+    ///
+    /// Nothing is picked up while snapshots of the store are alive.
+    pub fn refresh(&self) -> Result<()> {
+        if !self.core.options.read_only {
+            return Ok(());
+        }
+        retry_vanished(|| self.core.refresh())
+    }
+
+    /// Returns the hit and miss counters of the value cache, and how much it
+    /// holds. `None` if values are not cached, see
+    /// `KvStoreOptions::value_cache_size`.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.core.cache.as_ref().map(ValueCache::stats)
+    }
+
+    /// Sets `key` to `value`, or removes it if `value` is `None`, only if it
+    /// meets `condition`.
+    fn write_if(&self, key: Vec<u8>, condition: Condition, value: Option<Vec<u8>>) -> Result<()> {
+        let core = &self.core;
+        if core.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let op = WriteOp::Conditional {
+            key,
+            condition,
+            value,
+        };
+        core.commit_queue.submit(core, op)?;
+        self.try_compact_log();
+        Ok(())
+    }
+
+    /// Wakes the compaction thread up if enough of the logs is stale, or if
+    /// changes to the index are waiting to be merged into its file.
+    fn try_compact_log(&self) {
+        let core = &self.core;
+        if let Some(compactor) = &self.compactor {
+            if core.stats.lock().unwrap().needs_compaction(&core.options)
+                || core.kv_index.read().unwrap().is_frozen()
+            {
+                compactor.trigger();
+            }
+        }
+    }
+}
+
+impl StoreCore {
+    /// Brings the index of a read-only store up to date with the logs.
+    ///
+    /// The handles of the logs are kept open, so reads keep working when the
+    /// writer removes them.
+    ///
+    /// Nothing is picked up while snapshots are alive, the replay keeps no
+    /// older entries for them.
+    fn refresh(&self) -> Result<()> {
+        let mut scanned = self.scanned.lock().unwrap();
+        let (gens, mut hints) = recover_compactions(&self.path, true)?;
+        self.readers.open_all(&self.path, &gens)?;
+        // The writer only appends to the newest log.
+        if let Some(&newest) = gens.last() {
+            self.readers.seal_below(newest);
+        }
+
+        // Replaying logs out of order would bring old values back.
+        let last_scanned = scanned.keys().next_back().copied();
+        let rebuild = scanned.keys().any(|gen| !gens.contains(gen))
+            || gens
+                .iter()
+                .any(|gen| !scanned.contains_key(gen) && Some(*gen) < last_scanned);
+        if rebuild {
+            let loaded = load_logs(&self.path, &gens, hints, &self.options, KeyDir::default())?;
+            // RwLock: kv_index
+            let mut kv_index = self.kv_index.write().unwrap();
+            if self.snapshots.lock().unwrap().is_live() {
+                return Ok(());
+            }
+            *kv_index = loaded.kv_index;
+            self.last_version
+                .fetch_max(loaded.max_version, Ordering::SeqCst);
+            *self.stats.lock().unwrap() = loaded.stats;
+            *scanned = loaded.scanned;
+            self.readers.retain(&gens);
+            return Ok(());
+        }
+
+        // RwLock: kv_index
+        let mut kv_index = self.kv_index.write().unwrap();
+        if self.snapshots.lock().unwrap().is_live() {
+            return Ok(());
+        }
+        // Mutex: LogStats
+        let mut stats = self.stats.lock().unwrap();
+        for &gen in &gens {
+            let start = scanned
+                .get(&gen)
+                .copied()
+                .filter(|&end| end >= FILE_MAGIC.len() as u64);
+            let hint = hints.remove(&gen);
+            let replayed = load(&self.path, gen, start, hint, &mut kv_index, &mut stats)?;
+            check_damage(&self.path, gen, replayed, &gens, &self.options)?;
+            scanned.insert(gen, replayed.end);
+            self.last_version
+                .fetch_max(replayed.max_version, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn writer(&self) -> &Mutex<LogWriter> {
+        self.writer
+            .as_ref()
+            .expect("read-only stores have no log writer")
+    }
+
+    /// Seals the active log and moves writes on to the log of `gen`.
+    fn roll_writer(&self, writer: &mut LogWriter, gen: u64) -> Result<()> {
+        // A new log would seal the partial write in the middle of the logs.
+        writer.check_poisoned()?;
+        if self.options.sync_policy != SyncPolicy::Os {
+            writer.sync()?;
+        }
+        *writer = LogWriter::open(&self.path, gen)?;
+        self.readers.seal_below(gen);
+        Ok(())
+    }
+
+    /// Syncs the writes of the active log that are not synced yet, without
+    /// holding up new writes in the meantime.
+    ///
+    /// If the sync fails, the writes still count as unsynced, so that the
+    /// next sync tries again.
+    fn sync_log(&self) -> Result<()> {
+        let (gen, unsynced, file) = {
+            // Mutex: LogWriter
+            let mut writer = self.writer().lock().unwrap();
+            if writer.unsynced == 0 {
+                return Ok(());
+            }
+            let file = writer.file.try_clone()?;
+            (writer.gen, mem::take(&mut writer.unsynced), file)
+        };
+        if let Err(err) = file.sync_data() {
+            // Mutex: LogWriter
+            let mut writer = self.writer().lock().unwrap();
+            // A log rolled over in the meantime was synced as it was sealed.
+            if writer.gen == gen {
+                writer.unsynced += unsynced;
+            }
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Reads the value of `key`, found at `cmd_pos` in the index, from the
+    /// cache if it has the version of `cmd_pos`.
+    fn read_value(&self, key: &[u8], cmd_pos: CommandPos) -> Result<Option<Vec<u8>>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(self.read_set(key, cmd_pos)?.map(|record| record.value)),
+        };
+        if !is_expired(cmd_pos.expires_at, now_millis()) {
+            if let Some(value) = cache.get(key, cmd_pos.version) {
+                return Ok(Some(value));
+            }
+        }
+        Ok(self.read_set(key, cmd_pos)?.map(|record| {
+            cache.insert(key.to_vec(), record.version, record.value.clone());
+            record.value
+        }))
+    }
+
+    /// Reads the set record of `key`, found at `cmd_pos` in the index.
+    fn read_set(&self, key: &[u8], mut cmd_pos: CommandPos) -> Result<Option<Record>> {
+        loop {
+            if is_expired(cmd_pos.expires_at, now_millis()) {
+                self.expire(key, cmd_pos)?;
+                return Ok(None);
+            }
+            let result = self.read_from_log(cmd_pos);
+            // A compaction moved the entry and removed its log file in the
+            // meantime. Look it up again.
+            if let Err(KvsError::Io(ref err)) = result {
+                let current = self.kv_index.read().unwrap().get(key)?;
+                if err.kind() == io::ErrorKind::NotFound && current != Some(cmd_pos) {
+                    match current {
+                        Some(new_pos) => {
+                            cmd_pos = new_pos;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+            }
+            return result;
+        }
+    }
+
+    /// Drops the entries that have expired by now from the index, so that
+    /// their records count as stale. The index is gone through a chunk at a
+    /// time, so that writes are not held up.
+    fn expire_all(&self) -> Result<()> {
+        if !self.has_expiring.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let now = now_millis();
+        let mut start = Bound::Unbounded;
+        loop {
+            let entries = self.kv_index.read().unwrap().entries(
+                (start, Bound::Unbounded),
+                false,
+                INDEX_CHUNK,
+            )?;
+            let last_key = match entries.last() {
+                Some((key, _)) => key.clone(),
+                None => return Ok(()),
+            };
+            for (key, cmd_pos) in entries {
+                if is_expired(cmd_pos.expires_at, now) {
+                    self.expire(&key, cmd_pos)?;
+                }
+            }
+            start = Bound::Excluded(last_key);
+        }
+    }
+
+    /// Drops the expired entry of `key` at `cmd_pos` from the index, unless
+    /// the key was written again in the meantime. Its record becomes stale.
+    fn expire(&self, key: &[u8], cmd_pos: CommandPos) -> Result<()> {
+        // RwLock: kv_index
+        let mut kv_index = self.kv_index.write().unwrap();
+        if kv_index.get(key)? == Some(cmd_pos) {
+            kv_index.remove(key)?;
+            if let Some(cache) = &self.cache {
+                cache.remove(key);
+            }
+            // Mutex: LogStats
+            self.stats
+                .lock()
+                .unwrap()
+                .mark_stale(cmd_pos.gen, cmd_pos.len);
+        }
+        Ok(())
+    }
+
+    fn read_from_log(&self, cmd_pos: CommandPos) -> Result<Option<Record>> {
+        let record = self
+            .readers
+            .read_with(&self.path, cmd_pos, Record::decode)?;
+        let record = record.map_err(|_| KvsError::CorruptedLog {
+            gen: cmd_pos.gen,
+            offset: cmd_pos.pos,
+        })?;
+        match record.record_type {
+            RecordType::Set => Ok(Some(record)),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+}
+
+impl LogWriter {
+    /// Opens the log file of `gen` for appending, creating it if needed.
+    fn open(dir: &Path, gen: u64) -> Result<LogWriter> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(dir, gen))?;
+        let mut pos = file.metadata()?.len();
+        if pos == 0 {
+            file.write_all(FILE_MAGIC)?;
+            pos = FILE_MAGIC.len() as u64;
+        }
+        Ok(LogWriter {
+            gen,
+            pos,
+            file,
+            unsynced: 0,
+            poisoned: false,
+        })
+    }
+
+    /// Appends `buf` to the log and syncs it as `sync_policy` requires.
+    ///
+    /// If either fails, the log is truncated back to where it was, so that
+    /// later records are written where the index expects them and a replay
+    /// does not stop short of them. If even that fails, the writer is
+    /// poisoned.
+    fn append(&mut self, buf: &[u8], sync_policy: SyncPolicy) -> Result<()> {
+        self.check_poisoned()?;
+        let (pos, unsynced) = (self.pos, self.unsynced);
+        let written = self
+            .file
+            .write_all(buf)
+            .map_err(KvsError::from)
+            .and_then(|()| {
+                self.pos += buf.len() as u64;
+                self.unsynced += buf.len() as u64;
+                match sync_policy {
+                    SyncPolicy::Always => self.sync(),
+                    SyncPolicy::EveryBytes(bytes) if self.unsynced >= bytes => self.sync(),
+                    _ => Ok(()),
+                }
+            });
+        if written.is_err() {
+            self.pos = pos;
+            self.unsynced = unsynced;
+            if let Err(err) = self.file.set_len(pos) {
+                error!(
+                    "unable to undo a failed write to log {}, refusing further writes: {}",
+                    self.gen, err
+                );
+                self.poisoned = true;
+            }
+        }
+        written
+    }
+
+    fn check_poisoned(&self) -> Result<()> {
+        if self.poisoned {
+            return Err(KvsError::StringError(format!(
+                "log {} has a partial write at its end, reopen the store",
+                self.gen
+            )));
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+}
+
+impl LogReaders {
+    /// Logs can't be removed while they are mapped on Windows, where `mmap`
+    /// is ignored.
+    fn new(mmap: bool) -> LogReaders {
+        LogReaders {
+            files: RwLock::new(HashMap::new()),
+            maps: (mmap && cfg!(unix)).then(|| RwLock::new(HashMap::new())),
+            sealed_below: AtomicU64::new(0),
+            safe_gen: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads the raw bytes of the command at `cmd_pos`.
+    fn read(&self, dir: &Path, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        match self.map(dir, cmd_pos.gen)? {
+            Some(map) => Ok(map_slice(&map, cmd_pos)?.to_vec()),
+            None => self.read_file(dir, cmd_pos),
+        }
+    }
+
+    /// Hands the raw bytes of the command at `cmd_pos` to `f`, straight from
+    /// the map of its log if it has one.
+    fn read_with<T>(
+        &self,
+        dir: &Path,
+        cmd_pos: CommandPos,
+        f: impl FnOnce(&[u8]) -> T,
+    ) -> Result<T> {
+        match self.map(dir, cmd_pos.gen)? {
+            Some(map) => Ok(f(map_slice(&map, cmd_pos)?)),
+            None => Ok(f(&self.read_file(dir, cmd_pos)?)),
+        }
+    }
+
+    fn read_file(&self, dir: &Path, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        let file = self.file(dir, cmd_pos.gen)?;
+        let mut record = vec![0; cmd_pos.len as usize];
+        read_exact_at(&file, &mut record, cmd_pos.pos)?;
+        Ok(record)
+    }
+
+    /// Returns the handle of the log of `gen`, opening it on first use.
+    fn file(&self, dir: &Path, gen: u64) -> Result<Arc<File>> {
+        if let Some(file) = self.files.read().unwrap().get(&gen) {
+            return Ok(Arc::clone(file));
+        }
+        let file = Arc::new(File::open(log_path(dir, gen))?);
+        let mut files = self.files.write().unwrap();
+        if gen >= self.safe_gen.load(Ordering::SeqCst) {
+            files.insert(gen, Arc::clone(&file));
+        }
+        Ok(file)
+    }
+
+    /// Returns the map of the log of `gen`, mapping it on first use, if reads
+    /// go through maps and the log is sealed.
+    fn map(&self, dir: &Path, gen: u64) -> Result<Option<Arc<Mmap>>> {
+        let maps = match &self.maps {
+            Some(maps) if gen < self.sealed_below.load(Ordering::SeqCst) => maps,
+            _ => return Ok(None),
+        };
+        if let Some(map) = maps.read().unwrap().get(&gen) {
+            return Ok(Some(Arc::clone(map)));
+        }
+        let file = self.file(dir, gen)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(None);
+        }
+        // SAFETY: sealed logs are never written to again. They are only
+        // removed, which leaves the maps of a removed file readable.
+        let map = Arc::new(unsafe { Mmap::map(&*file)? });
+        let mut maps = maps.write().unwrap();
+        if gen >= self.safe_gen.load(Ordering::SeqCst) {
+            maps.insert(gen, Arc::clone(&map));
+        }
+        Ok(Some(map))
+    }
+
+    /// Marks the logs below `gen` as sealed, once writes have moved on to
+    /// `gen`.
+    fn seal_below(&self, gen: u64) {
+        self.sealed_below.fetch_max(gen, Ordering::SeqCst);
+    }
+
+    /// Returns the handle of `gen` if it is open.
+    fn get(&self, gen: u64) -> Option<Arc<File>> {
+        self.files.read().unwrap().get(&gen).cloned()
+    }
+
+    /// Opens the handles of `gens` that are not open yet.
+    fn open_all(&self, dir: &Path, gens: &[u64]) -> Result<()> {
+        let mut files = self.files.write().unwrap();
+        for &gen in gens {
+            if let Entry::Vacant(entry) = files.entry(gen) {
+                entry.insert(Arc::new(File::open(log_path(dir, gen))?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the handles and maps of the generations other than `gens`.
+    fn retain(&self, gens: &[u64]) {
+        self.files
+            .write()
+            .unwrap()
+            .retain(|file_gen, _| gens.contains(file_gen));
+        if let Some(maps) = &self.maps {
+            maps.write()
+                .unwrap()
+                .retain(|map_gen, _| gens.contains(map_gen));
+        }
+    }
+
+    /// Drops the handles and maps of generations below `gen`, which are
+    /// about to be removed. Reads in flight keep their own.
+    fn close_below(&self, gen: u64) {
+        self.safe_gen.store(gen, Ordering::SeqCst);
+        self.files
+            .write()
+            .unwrap()
+            .retain(|&file_gen, _| file_gen >= gen);
+        if let Some(maps) = &self.maps {
+            maps.write().unwrap().retain(|&map_gen, _| map_gen >= gen);
+        }
+    }
+}
+
+impl<F, R> ChunkedScan<F, R>
+where
+    F: FnMut(KeyRange, usize) -> Result<Chunk>,
+    R: FnMut(&[u8], CommandPos) -> Result<Option<Vec<u8>>>,
+{
+    fn new(range: KeyRange, options: ScanOptions, next_chunk: F, read_value: R) -> Self {
+        ChunkedScan {
+            next_chunk,
+            read_value,
+            done: is_empty_range(&range),
+            range,
+            reverse: options.reverse,
+            remaining: options.limit,
+            chunk: Vec::new().into_iter(),
+        }
+    }
+
+    /// Takes the next chunk of entries, returning whether there was one.
+    fn advance(&mut self) -> Result<bool> {
+        if self.done || is_empty_range(&self.range) {
+            return Ok(false);
+        }
+        // A scan with a small limit reads no more than it can return.
+        let size = self
+            .remaining
+            .map_or(INDEX_CHUNK, |remaining| remaining.min(INDEX_CHUNK));
+        let (entries, last_key) = (self.next_chunk)(self.range.clone(), size)?;
+        match last_key {
+            Some(key) if self.reverse => self.range.1 = Bound::Excluded(key),
+            Some(key) => self.range.0 = Bound::Excluded(key),
+            None => self.done = true,
+        }
+        self.chunk = entries.into_iter();
+        Ok(true)
+    }
+}
+
+impl<F, R> Iterator for ChunkedScan<F, R>
+where
+    F: FnMut(KeyRange, usize) -> Result<Chunk>,
+    R: FnMut(&[u8], CommandPos) -> Result<Option<Vec<u8>>>,
+{
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining != Some(0) {
+            let (key, cmd_pos) = match self.chunk.next() {
+                Some(entry) => entry,
+                None => match self.advance() {
+                    Ok(true) => continue,
+                    Ok(false) => return None,
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err));
+                    }
+                },
+            };
+            match (self.read_value)(&key, cmd_pos) {
+                Ok(Some(value)) => {
+                    if let Some(remaining) = &mut self.remaining {
+                        *remaining -= 1;
+                    }
+                    return Some(Ok((key, value)));
+                }
+                Ok(None) => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        None
+    }
+}
+
+/// Returns the bytes of the command at `cmd_pos` in the map of its log.
+fn map_slice(map: &Mmap, cmd_pos: CommandPos) -> io::Result<&[u8]> {
+    let start = cmd_pos.pos as usize;
+    map.get(start..start + cmd_pos.len as usize)
+        .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Runs `f` again while it fails because a log file it needed was removed,
+/// which happens when a read-only store races with the compactions of the
+/// writer.
+fn retry_vanished(mut f: impl FnMut() -> Result<()>) -> Result<()> {
+    let mut attempts = 0;
+    loop {
+        match f() {
+            Err(KvsError::Io(ref err))
+                if err.kind() == io::ErrorKind::NotFound && attempts < 10 =>
+            {
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Makes the creations, renames and removals of files in `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be synced on Windows, metadata changes are journaled.
+#[cfg(windows)]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Replays the logs of `gens` into `kv_index`, with their `hints`.
+///
+/// If the index was opened from a key directory file, only the writes the
+/// file does not cover are replayed, and the byte counts of the logs are taken
+/// from it.
+///
+/// Only the tail of the newest log may be damaged, usually by a crash in the
+/// middle of a write. It is dropped unless the options are strict. A read-only
+/// store ignores it instead, it may also be a write in progress.
+fn load_logs(
+    path: &Path,
+    gens: &[u64],
+    mut hints: HashMap<u64, Hint>,
+    options: &KvStoreOptions,
+    kv_index: KeyDir,
+) -> Result<LoadedLogs> {
+    let coverage = kv_index.coverage().cloned();
+    let mut loaded = LoadedLogs {
+        kv_index,
+        ..LoadedLogs::default()
+    };
+    if let Some(coverage) = &coverage {
+        // A log covered from its start is counted again as it is loaded.
+        let logs = coverage
+            .logs
+            .iter()
+            .filter(|&&(gen, ..)| gens.contains(&gen) && (gen != coverage.gen || coverage.pos > 0));
+        loaded.stats = LogStats::from_gen_stats(logs.copied());
+        loaded.max_version = coverage.max_version;
+    }
+    for &gen in gens {
+        let start = match &coverage {
+            Some(coverage) if gen < coverage.gen => continue,
+            Some(coverage) if gen == coverage.gen && coverage.pos > 0 => Some(coverage.pos),
+            _ => None,
+        };
+        let hint = hints.remove(&gen);
+        let replayed = load(
+            path,
+            gen,
+            start,
+            hint,
+            &mut loaded.kv_index,
+            &mut loaded.stats,
+        )?;
+        check_damage(path, gen, replayed, gens, options)?;
+        loaded.scanned.insert(gen, replayed.end);
+        loaded.max_version = loaded.max_version.max(replayed.max_version);
+        loaded.kv_index.note_version(loaded.max_version);
+    }
+    Ok(loaded)
+}
+
+/// Deals with the damaged tail of the log of `gen`, if `replayed` found one.
+fn check_damage(
+    path: &Path,
+    gen: u64,
+    replayed: Replayed,
+    gens: &[u64],
+    options: &KvStoreOptions,
+) -> Result<()> {
+    if !replayed.damaged {
+        return Ok(());
+    }
+    if options.strict || Some(&gen) != gens.last() {
+        return Err(KvsError::CorruptedLog {
+            gen,
+            offset: replayed.end,
+        });
+    }
+    if options.read_only {
+        return Ok(());
+    }
+    let file = OpenOptions::new().write(true).open(log_path(path, gen))?;
+    let len = file.metadata()?.len();
+    warn!(
+        "dropping {} bytes of damaged records at offset {} of log {}",
+        len - replayed.end,
+        replayed.end,
+        gen
+    );
+    file.set_len(replayed.end)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Replays the log file of `gen` into `kv_index` and counts its stale bytes.
+///
+/// Replays the records from the offset `start` if it is given. Otherwise, if
+/// the log has a valid `hint` file, the entries it covers are taken from
+/// there and only the records written after it are read.
+///
+/// Stops at the end of the file or at the first damaged or incomplete record,
+/// which is where the valid part of the file ends.
+///
+/// A bounded index merges its changes into its file whenever they outgrow the
+/// budget. Entries the file already has are taken as they are.
+fn load(
+    dir: &Path,
+    gen: u64,
+    start: Option<u64>,
+    hint: Option<Hint>,
+    kv_index: &mut KeyDir,
+    stats: &mut LogStats,
+) -> Result<Replayed> {
+    let file = File::open(log_path(dir, gen))?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let damaged_at = |end| {
+        Ok(Replayed {
+            end,
+            damaged: true,
+            max_version: 0,
+        })
+    };
+    if let Some(start) = start {
+        reader.seek(SeekFrom::Start(start))?;
+        return replay_records(&mut reader, gen, start, kv_index, stats);
+    }
+
+    let mut magic = [0; FILE_MAGIC.len()];
+    match reader.read_exact(&mut magic) {
+        Ok(()) if &magic == FILE_MAGIC => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && len == 0 => {
+            return Ok(Replayed {
+                end: 0,
+                damaged: false,
+                max_version: 0,
+            })
+        }
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return damaged_at(0),
+        Ok(()) => return damaged_at(0),
+        Err(err) => return Err(err.into()),
+    }
+
+    let mut pos = FILE_MAGIC.len() as u64;
+    let mut max_version = 0;
+    match hint {
+        Some(hint) if hint.log_len >= pos && hint.log_len <= len => {
+            let now = now_millis();
+            max_version = hint.max_version;
+            for entry in hint.entries()? {
+                let entry = entry?;
+                stats.add(gen, entry.len);
+                max_version = max_version.max(entry.version);
+                let cmd_pos = CommandPos {
+                    gen,
+                    pos: entry.pos,
+                    len: entry.len,
+                    expires_at: entry.expires_at,
+                    version: entry.version,
+                };
+                let old_pos = if is_expired(entry.expires_at, now) {
+                    stats.mark_stale(gen, entry.len);
+                    kv_index.remove(&entry.key)?
+                } else {
+                    kv_index.insert(entry.key, cmd_pos)?
+                };
+                match old_pos {
+                    Some(old_pos) if old_pos != cmd_pos => {
+                        stats.mark_stale(old_pos.gen, old_pos.len)
+                    }
+                    _ => {}
+                }
+                if kv_index.needs_freeze() {
+                    // The rest of the hint is only known to the log, which
+                    // has to be loaded again from its start.
+                    kv_index.note_version(max_version);
+                    kv_index.merge_now(gen, 0, stats)?;
+                }
+            }
+            pos = hint.log_len;
+            reader.seek(SeekFrom::Start(pos))?;
+        }
+        Some(_) => warn!("ignoring the hint file of log {}, it does not match", gen),
+        None => {}
+    }
+
+    let replayed = replay_records(&mut reader, gen, pos, kv_index, stats)?;
+    Ok(Replayed {
+        max_version: replayed.max_version.max(max_version),
+        ..replayed
+    })
+}
+
+/// Replays the records of the log of `gen` that `reader` holds from `pos` on.
+///
+/// Sets that have expired by now count as removals. The records of a write
+/// batch are only replayed once its commit marker is read. A batch that is
+/// not committed by the end of the log, or whose markers don't match, is
+/// treated as damaged from its begin marker on.
+fn replay_records(
+    reader: &mut impl Read,
+    gen: u64,
+    mut pos: u64,
+    kv_index: &mut KeyDir,
+    stats: &mut LogStats,
+) -> Result<Replayed> {
+    let now = now_millis();
+    let mut batch: Option<PendingBatch> = None;
+    let mut max_version = 0;
+    loop {
+        let end = batch.as_ref().map_or(pos, |batch| batch.start);
+        let (record, len) = match Record::read_from(reader) {
+            Ok(Some(next)) => next,
+            Ok(None) => {
+                return Ok(Replayed {
+                    end,
+                    damaged: batch.is_some(),
+                    max_version,
+                })
+            }
+            Err(err)
+                if err.kind() == io::ErrorKind::InvalidData
+                    || err.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                return Ok(Replayed {
+                    end,
+                    damaged: true,
+                    max_version,
+                });
+            }
+            Err(err) => return Err(err.into()),
+        };
+        match (record.record_type, &mut batch) {
+            (RecordType::BatchBegin, None) => {
+                batch = Some(PendingBatch {
+                    start: pos,
+                    begin_len: len,
+                    records: Vec::new(),
+                })
+            }
+            (RecordType::BatchCommit, Some(_)) => {
+                let PendingBatch {
+                    begin_len, records, ..
+                } = batch.take().unwrap();
+                // The markers are only needed until the batch is compacted.
+                stats.add(gen, begin_len + len);
+                stats.mark_stale(gen, begin_len + len);
+                for (record, pos, len) in records {
+                    max_version = max_version.max(record.version);
+                    replay_record(record, gen, pos, len, now, kv_index, stats)?;
+                }
+            }
+            (RecordType::BatchBegin, Some(_)) | (RecordType::BatchCommit, None) => {
+                return Ok(Replayed {
+                    end,
+                    damaged: true,
+                    max_version,
+                });
+            }
+            (_, Some(batch)) => batch.records.push((record, pos, len)),
+            (_, None) => {
+                max_version = max_version.max(record.version);
+                replay_record(record, gen, pos, len, now, kv_index, stats)?;
+            }
+        }
+        pos += len;
+        if batch.is_none() && kv_index.needs_freeze() {
+            kv_index.note_version(max_version);
+            kv_index.merge_now(gen, pos, stats)?;
+        }
+    }
+}
+
+/// A write batch being replayed whose commit marker is not read yet.
+struct PendingBatch {
+    /// Position of the begin marker.
+    start: u64,
+    begin_len: u64,
+    /// Records of the batch, with their positions and lengths.
+    records: Vec<(Record, u64, u64)>,
+}
+
+/// Applies a set or a removal read at `pos` in the log of `gen` to
+/// `kv_index`.
+fn replay_record(
+    record: Record,
+    gen: u64,
+    pos: u64,
+    len: u64,
+    now: u64,
+    kv_index: &mut KeyDir,
+    stats: &mut LogStats,
+) -> Result<()> {
+    let key = record.key;
+    stats.add(gen, len);
+    let cmd_pos = CommandPos {
+        gen,
+        pos,
+        len,
+        expires_at: record.expires_at,
+        version: record.version,
+    };
+    let old_pos = match record.record_type {
+        RecordType::Set if !is_expired(record.expires_at, now) => kv_index.insert(key, cmd_pos)?,
+        _ => {
+            stats.mark_stale(gen, len);
+            kv_index.remove(&key)?
+        }
+    };
+    match old_pos {
+        // Already in the key directory file.
+        Some(old_pos) if old_pos == cmd_pos => {}
+        Some(old_pos) => stats.mark_stale(old_pos.gen, old_pos.len),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Converts logs written as concatenated JSON commands to the binary format.
+///
+/// A `log.json` left by versions without generations becomes generation 0.
+/// Each file is converted into a temporary one that replaces it atomically, so
+/// an interrupted migration is simply run again on the next open.
+fn migrate_json_logs(path: &Path) -> Result<()> {
+    let legacy_log_path = path.join(LEGACY_LOG_NAME);
+    if legacy_log_path.exists() {
+        if log_path(path, 0).exists() {
+            return Err(KvsError::StringError(format!(
+                "both {} and {} exist",
+                LEGACY_LOG_NAME,
+                log_path(path, 0).display()
+            )));
+        }
+        convert_json_log(path, &legacy_log_path, 0)?;
+        fs::remove_file(&legacy_log_path)?;
+        sync_dir(path)?;
+    }
+
+    for gen in sorted_gen_list(path)? {
+        let gen_path = log_path(path, gen);
+        let mut first_byte = [0; 1];
+        let len = File::open(&gen_path)?.read(&mut first_byte)?;
+        if len == 1 && first_byte[0] == b'{' {
+            convert_json_log(path, &gen_path, gen)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `path` holds logs that `migrate_json_logs` has to convert.
+fn has_json_logs(path: &Path) -> Result<bool> {
+    if path.join(LEGACY_LOG_NAME).exists() {
+        return Ok(true);
+    }
+    for gen in sorted_gen_list(path)? {
+        let mut first_byte = [0; 1];
+        let len = File::open(log_path(path, gen))?.read(&mut first_byte)?;
+        if len == 1 && first_byte[0] == b'{' {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Rewrites the JSON log `src` as the log of generation `gen` in `dir`.
+fn convert_json_log(dir: &Path, src: &Path, gen: u64) -> Result<()> {
+    let dest = log_path(dir, gen);
+    let tmp_path = dest.with_extension("log.tmp");
+    let mut tmp_file = File::create(&tmp_path)?;
+    tmp_file.write_all(FILE_MAGIC)?;
+
+    let reader = BufReader::new(File::open(src)?);
+    for cmd in Deserializer::from_reader(reader).into_iter::<Command>() {
+        let record = match cmd? {
+            Command::Set { key, value, .. } => Record::set(key, value, None),
+            Command::Rm { key, .. } => Record::remove(key),
+            // Only sets and removals were ever logged.
+            _ => continue,
+        };
+        tmp_file.write_all(&record.encode()?)?;
+    }
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, &dest)?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// Returns the sorted generation numbers of the log files in `path`.
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    sorted_gens_with_extension(path, "log")
+}
+
+/// Returns the sorted generation numbers of the files in `path` named
+/// `<gen>.<extension>`.
+fn sorted_gens_with_extension(path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut gens: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|entry| entry.map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension() == Some(OsStr::new(extension)))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
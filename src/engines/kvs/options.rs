@@ -0,0 +1,174 @@
+use std::time::Duration;
+
+use crate::SyncPolicy;
+
+/// Default size in bytes after which the active log file is sealed and a new
+/// generation is started.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// Default fraction of stale bytes in the logs that triggers a compaction.
+pub const DEFAULT_GARBAGE_RATIO: f64 = 0.5;
+
+/// Default amount of stale bytes that triggers a compaction whatever the ratio.
+pub const DEFAULT_COMPACTION_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Default limit of the bytes per second copied by a compaction.
+pub const DEFAULT_COMPACTION_RATE: u64 = 32 * 1024 * 1024;
+
+/// Default interval between two looks for expired keys.
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Options used to open a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = KvStoreOptions::default().segment_size(64 * 1024 * 1024);
+/// let store = KvStore::open_with(current_dir()?, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) segment_size: u64,
+    pub(super) strict: bool,
+    pub(super) garbage_ratio: f64,
+    pub(super) compaction_threshold: u64,
+    pub(super) compaction_rate: Option<u64>,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_only: bool,
+    pub(super) sweep_interval: Duration,
+    pub(super) index_memory_budget: Option<u64>,
+    pub(super) value_cache_size: Option<u64>,
+    pub(super) mmap_reads: bool,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            strict: false,
+            garbage_ratio: DEFAULT_GARBAGE_RATIO,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_rate: Some(DEFAULT_COMPACTION_RATE),
+            sync_policy: SyncPolicy::Os,
+            read_only: false,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            index_memory_budget: None,
+            value_cache_size: None,
+            mmap_reads: false,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Sets the size in bytes after which the active log file is sealed and
+    /// writes move on to a new generation.
+    ///
+    /// Compaction output is written to a single generation and may exceed it.
+    pub fn segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size.max(1);
+        self
+    }
+
+    /// Refuses to open a store whose newest log ends in a damaged or
+    /// incomplete record, instead of truncating it.
+    ///
+    /// By default such a tail, usually left by a crash in the middle of a
+    /// write, is dropped with a warning. Damage anywhere else is always an
+    /// error.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets the fraction of stale bytes, between 0 and 1, at which a
+    /// compaction is started.
+    ///
+    /// The ratio is only considered once the logs hold at least one segment.
+    pub fn garbage_ratio(mut self, garbage_ratio: f64) -> Self {
+        self.garbage_ratio = garbage_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the amount of stale bytes at which a compaction is started,
+    /// whatever their ratio.
+    pub fn compaction_threshold(mut self, compaction_threshold: u64) -> Self {
+        self.compaction_threshold = compaction_threshold.max(1);
+        self
+    }
+
+    /// Limits the bytes per second a compaction copies, so that it leaves
+    /// disk bandwidth to the clients. `None` lets it run at full speed.
+    pub fn compaction_rate(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.compaction_rate = bytes_per_sec.map(|rate| rate.max(1));
+        self
+    }
+
+    /// Sets when writes are synced to disk. Defaults to `SyncPolicy::Os`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets how often the compaction thread looks for expired keys. Their
+    /// records count as stale from then on, and compactions leave them out.
+    /// They are hidden as soon as they expire either way. Defaults to 1
+    /// second.
+    pub fn sweep_interval(mut self, sweep_interval: Duration) -> Self {
+        self.sweep_interval = sweep_interval;
+        self
+    }
+
+    /// Bounds the memory taken by the index to about `bytes`, for keyspaces
+    /// that don't fit in memory. `None`, the default, keeps every key in
+    /// memory.
+    ///
+    /// With a budget, the index is kept sorted in a `keydir` file next to the
+    /// logs, of which only one key per block stays in memory, along with the
+    /// recent changes. Looking a key up reads at most one block of the file.
+    /// The changes are merged into the file in the background, and `open`
+    /// only replays the writes made since the last merge.
+    ///
+    /// Read-only stores always keep their index in memory.
+    pub fn index_memory_budget(mut self, bytes: Option<u64>) -> Self {
+        self.index_memory_budget = bytes;
+        self
+    }
+
+    /// Keeps the values of the most recently read keys in memory, up to about
+    /// `bytes`, so that reading them again does not go to the logs. `None`,
+    /// the default, caches nothing.
+    ///
+    /// Writes drop the values they replace from the cache. See
+    /// `KvStore::cache_stats` for how well it does.
+    pub fn value_cache_size(mut self, bytes: Option<u64>) -> Self {
+        self.value_cache_size = bytes;
+        self
+    }
+
+    /// Reads the logs that are no longer written to through memory maps,
+    /// rather than with a read call per value. Defaults to false.
+    ///
+    /// Maps are made on the first read of a log, and dropped along with the
+    /// logs that compactions replace. The log being written to is always
+    /// read with its file handle. Ignored on Windows, where mapped files
+    /// can't be removed.
+    pub fn mmap_reads(mut self, mmap_reads: bool) -> Self {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+
+    /// Opens the store read-only, without taking the directory lock, so it
+    /// can be opened while another process writes to it.
+    ///
+    /// Writes fail with `KvsError::ReadOnly`. Nothing in the directory is
+    /// written or removed: a damaged tail is ignored rather than truncated,
+    /// and logs in the JSON format of older versions are refused. Call
+    /// `KvStore::refresh` to see the writes made since the open.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+}
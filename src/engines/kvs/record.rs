@@ -0,0 +1,288 @@
+//! Binary record format of the `KvStore` log files.
+//!
+//! Every log file starts with `FILE_MAGIC`, followed by records laid out as
+//!
+//! ```text
+//! +------------+----------+--------------+----------------+------+-----+-------+
+//! | crc32: u32 | type: u8 | key_len: u32 | value_len: u32 | meta | key | value |
+//! +------------+----------+--------------+----------------+------+-----+-------+
+//! ```
+//!
+//! Integers are little-endian. The checksum covers everything after itself.
+//!
+//! The meta of sets and removals is the version of the write and the time it
+//! was made, in milliseconds since the Unix epoch. Sets also have the time
+//! their value expires, 0 if it never does:
+//!
+//! ```text
+//! +--------------+------------------+-------------------+
+//! | version: u64 | modified_at: u64 | [expires_at: u64] |
+//! +--------------+------------------+-------------------+
+//! ```
+//!
+//! Records converted from the JSON logs have version 0.
+//!
+//! The records of a write batch are enclosed in a `BatchBegin` and a
+//! `BatchCommit` marker, with empty keys and values and no meta. Records
+//! after a begin marker whose commit marker is missing are discarded on
+//! replay.
+
+use std::io::{self, Read};
+
+/// Marks a log file written in the binary record format.
+pub const FILE_MAGIC: &[u8; 8] = b"KVSLOG\x00\x01";
+
+/// Length of the fixed-size part of a record.
+pub const HEADER_LEN: u64 = 13;
+
+/// Length of the meta of a removal.
+const REMOVE_META_LEN: usize = 16;
+
+/// Length of the meta of a set.
+const SET_META_LEN: usize = 24;
+
+/// Kind of a log record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    /// Sets a key to a value.
+    Set = 1,
+    /// Removes a key. The value is empty.
+    Remove = 2,
+    /// Starts a write batch.
+    BatchBegin = 3,
+    /// Ends a write batch, whose records take effect from then on.
+    BatchCommit = 4,
+}
+
+impl RecordType {
+    fn from_u8(byte: u8) -> Option<RecordType> {
+        match byte {
+            1 => Some(RecordType::Set),
+            2 => Some(RecordType::Remove),
+            3 => Some(RecordType::BatchBegin),
+            4 => Some(RecordType::BatchCommit),
+            _ => None,
+        }
+    }
+
+    /// Length of the meta of the records of this type.
+    fn meta_len(self) -> usize {
+        match self {
+            RecordType::Set => SET_META_LEN,
+            RecordType::Remove => REMOVE_META_LEN,
+            RecordType::BatchBegin | RecordType::BatchCommit => 0,
+        }
+    }
+}
+
+/// A decoded log record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub record_type: RecordType,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// When the value of a set expires, in milliseconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+    /// Sequence number of the write, 0 for markers and converted records.
+    pub version: u64,
+    /// When the write was made, in milliseconds since the Unix epoch.
+    pub modified_at: u64,
+}
+
+impl Record {
+    pub fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Record {
+        Record {
+            record_type: RecordType::Set,
+            key,
+            value,
+            expires_at,
+            version: 0,
+            modified_at: 0,
+        }
+    }
+
+    pub fn remove(key: Vec<u8>) -> Record {
+        Record {
+            record_type: RecordType::Remove,
+            key,
+            value: Vec::new(),
+            expires_at: None,
+            version: 0,
+            modified_at: 0,
+        }
+    }
+
+    /// Gives a set or a removal the version of its write, which it is then
+    /// encoded with.
+    pub fn versioned(mut self, version: u64, modified_at: u64) -> Record {
+        self.version = version;
+        self.modified_at = modified_at;
+        self
+    }
+
+    /// Returns a `BatchBegin` or `BatchCommit` marker.
+    pub fn marker(record_type: RecordType) -> Record {
+        Record {
+            record_type,
+            key: Vec::new(),
+            value: Vec::new(),
+            expires_at: None,
+            version: 0,
+            modified_at: 0,
+        }
+    }
+
+    /// Serializes the record, header included.
+    ///
+    /// Fails with `InvalidInput` if the key or the value does not fit its
+    /// length field.
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        check_lengths(&self.key, &self.value)?;
+        let mut buf = Vec::with_capacity(
+            HEADER_LEN as usize + SET_META_LEN + self.key.len() + self.value.len(),
+        );
+        buf.extend_from_slice(&[0; 4]);
+        buf.push(self.record_type as u8);
+        buf.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.value.len() as u32).to_le_bytes());
+        if self.record_type.meta_len() > 0 {
+            buf.extend_from_slice(&self.version.to_le_bytes());
+            buf.extend_from_slice(&self.modified_at.to_le_bytes());
+        }
+        if self.record_type == RecordType::Set {
+            buf.extend_from_slice(&self.expires_at.unwrap_or(0).to_le_bytes());
+        }
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.value);
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        Ok(buf)
+    }
+
+    /// Deserializes a whole record, as returned by a positioned read of its
+    /// known length.
+    ///
+    /// Fails with `InvalidData` if the framing or the checksum is wrong.
+    pub fn decode(buf: &[u8]) -> io::Result<Record> {
+        if buf.len() < HEADER_LEN as usize {
+            return Err(invalid_data("record shorter than its header"));
+        }
+        let (header, body) = buf.split_at(HEADER_LEN as usize);
+        let header = parse_header(header)?;
+        if body.len() != header.body_len() {
+            return Err(invalid_data("record length does not match its header"));
+        }
+        check_crc(&buf[..HEADER_LEN as usize], body)?;
+        Ok(header.record(body.to_vec()))
+    }
+
+    /// Reads the next record from a log stream, returning it with its length.
+    ///
+    /// Returns `None` at a clean end of the stream. A record cut short fails
+    /// with `UnexpectedEof`, a damaged one with `InvalidData`.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Option<(Record, u64)>> {
+        let mut header = [0; HEADER_LEN as usize];
+        let mut filled = 0;
+        while filled < header.len() {
+            match reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let parsed = parse_header(&header)?;
+        // A damaged header may claim a huge body, so don't allocate it upfront.
+        let body_len = parsed.body_len() as u64;
+        let mut body = Vec::new();
+        reader.take(body_len).read_to_end(&mut body)?;
+        if (body.len() as u64) < body_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        check_crc(&header, &body)?;
+
+        let len = HEADER_LEN + body_len;
+        Ok(Some((parsed.record(body), len)))
+    }
+}
+
+/// Checks that `key` and `value` fit the length fields of a record.
+pub fn check_lengths(key: &[u8], value: &[u8]) -> io::Result<()> {
+    if u32::try_from(key.len()).is_err() {
+        return Err(invalid_input("key longer than 4 GiB"));
+    }
+    if u32::try_from(value.len()).is_err() {
+        return Err(invalid_input("value longer than 4 GiB"));
+    }
+    Ok(())
+}
+
+/// Fields of a record header.
+struct Header {
+    record_type: RecordType,
+    key_len: usize,
+    value_len: usize,
+}
+
+impl Header {
+    fn body_len(&self) -> usize {
+        self.record_type.meta_len() + self.key_len + self.value_len
+    }
+
+    /// Splits the checked `body` of the record into its fields.
+    fn record(&self, mut body: Vec<u8>) -> Record {
+        let field =
+            |offset: usize| u64::from_le_bytes(body[offset..offset + 8].try_into().unwrap());
+        let (version, modified_at) = match self.record_type.meta_len() {
+            0 => (0, 0),
+            _ => (field(0), field(8)),
+        };
+        let expires_at = match self.record_type {
+            RecordType::Set => Some(field(16)).filter(|&expires_at| expires_at != 0),
+            _ => None,
+        };
+        body.drain(..self.record_type.meta_len());
+        let value = body.split_off(self.key_len);
+        Record {
+            record_type: self.record_type,
+            key: body,
+            value,
+            expires_at,
+            version,
+            modified_at,
+        }
+    }
+}
+
+fn parse_header(header: &[u8]) -> io::Result<Header> {
+    let record_type =
+        RecordType::from_u8(header[4]).ok_or_else(|| invalid_data("unknown record type"))?;
+    let key_len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+    let value_len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+    Ok(Header {
+        record_type,
+        key_len,
+        value_len,
+    })
+}
+
+fn check_crc(header: &[u8], body: &[u8]) -> io::Result<()> {
+    let expected = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(body);
+    if hasher.finalize() != expected {
+        return Err(invalid_data("checksum mismatch"));
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,284 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::ops::RangeBounds;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use super::{Chunk, ChunkedScan, CommandPos, StoreCore};
+use crate::engines::expiry::{is_expired, now_millis};
+use crate::engines::scan::{key_range, KeyRange};
+use crate::{KvsError, KvsSnapshot, Result, ScanIter, ScanOptions};
+
+/// Live snapshots of a `KvStore`, and the index entries they still need.
+///
+/// While a snapshot is alive, every write to the index records the entry it
+/// replaces along with the version of the write. A snapshot finds the entry of
+/// a key as of its version in the first record made by a newer write, or in
+/// the index if there is none. Records no snapshot can reach any more are
+/// dropped as snapshots are released.
+///
+/// Compactions don't move recorded entries. The logs they are in are retired
+/// instead of removed, until no record points into them.
+#[derive(Debug, Default)]
+pub(super) struct Snapshots {
+    /// Versions of the live snapshots, with how many there are of each.
+    live: BTreeMap<u64, usize>,
+    /// Entries replaced while snapshots were alive, oldest first, each with
+    /// the version of the write that replaced it. `None` stands for a key
+    /// that did not exist.
+    history: BTreeMap<Vec<u8>, Vec<(u64, Option<CommandPos>)>>,
+    /// Compacted logs kept for the entries of `history`.
+    retired_gens: BTreeSet<u64>,
+}
+
+impl Snapshots {
+    /// Whether writes have to record the entries they replace.
+    pub fn is_live(&self) -> bool {
+        !self.live.is_empty()
+    }
+
+    /// Records that the write of `version` replaced `old_pos`, the entry of
+    /// `key` in the index. Does nothing when no snapshot is alive.
+    pub fn record(&mut self, key: &[u8], version: u64, old_pos: Option<CommandPos>) {
+        if self.is_live() {
+            self.history
+                .entry(key.to_vec())
+                .or_default()
+                .push((version, old_pos));
+        }
+    }
+
+    /// Returns the entry of `key` as of `version` if a later write replaced
+    /// it, `None` if the index still has it.
+    fn lookup(&self, key: &[u8], version: u64) -> Option<Option<CommandPos>> {
+        self.history
+            .get(key)
+            .and_then(|entries| {
+                entries
+                    .iter()
+                    .find(|(replaced_by, _)| *replaced_by > version)
+            })
+            .map(|&(_, old_pos)| old_pos)
+    }
+
+    fn register(&mut self, version: u64) {
+        *self.live.entry(version).or_default() += 1;
+    }
+
+    /// Forgets a snapshot of `version`, and the entries only it needed.
+    fn release(&mut self, version: u64) {
+        if let Some(count) = self.live.get_mut(&version) {
+            *count -= 1;
+            if *count == 0 {
+                self.live.remove(&version);
+            }
+        }
+        // A snapshot needs the first entry replaced after its version, so
+        // the entries replaced up to the oldest one are of no use.
+        match self.live.keys().next() {
+            Some(&oldest) => self.history.retain(|_, entries| {
+                entries.retain(|(replaced_by, _)| *replaced_by > oldest);
+                !entries.is_empty()
+            }),
+            None => self.history.clear(),
+        }
+    }
+
+    /// Takes the compacted logs of `gens`, and returns those that can be
+    /// removed now. The others are retired until no snapshot needs them.
+    pub fn retire(&mut self, gens: &[u64]) -> Vec<u64> {
+        let needed = self.needed_gens();
+        let (retired, removable): (Vec<u64>, Vec<u64>) =
+            gens.iter().partition(|gen| needed.contains(gen));
+        for &gen in &removable {
+            self.retired_gens.remove(&gen);
+        }
+        self.retired_gens.extend(retired);
+        removable
+    }
+
+    /// Returns the retired logs that no snapshot needs any more, and forgets
+    /// them.
+    pub fn take_unneeded(&mut self) -> Vec<u64> {
+        let needed = self.needed_gens();
+        let (kept, unneeded) = self
+            .retired_gens
+            .iter()
+            .partition(|gen| needed.contains(gen));
+        self.retired_gens = kept;
+        unneeded.into_iter().collect()
+    }
+
+    fn needed_gens(&self) -> BTreeSet<u64> {
+        self.history
+            .values()
+            .flatten()
+            .filter_map(|(_, old_pos)| old_pos.map(|old_pos| old_pos.gen))
+            .collect()
+    }
+}
+
+/// A view of a `KvStore` as of the moment it was taken, returned by
+/// `KvsEngine::snapshot`.
+///
+/// Older values stay in the logs and their entries in memory until the
+/// snapshot is dropped, so long-lived snapshots hold up the space
+/// compactions would reclaim.
+#[derive(Debug)]
+pub struct KvStoreSnapshot {
+    handle: Arc<SnapshotHandle>,
+}
+
+/// Registration of a snapshot, released when the snapshot and its scans are
+/// gone.
+#[derive(Debug)]
+struct SnapshotHandle {
+    core: Arc<StoreCore>,
+    version: u64,
+}
+
+impl Drop for SnapshotHandle {
+    fn drop(&mut self) {
+        self.core.snapshots.lock().unwrap().release(self.version);
+    }
+}
+
+impl KvStoreSnapshot {
+    /// Registers a snapshot of the writes applied to the index so far.
+    pub(super) fn take(core: &Arc<StoreCore>) -> KvStoreSnapshot {
+        // Writes update the index and the last version together, under the
+        // write lock of the index.
+        // RwLock: kv_index
+        let _kv_index = core.kv_index.read().unwrap();
+        let version = core.last_version.load(Ordering::SeqCst);
+        core.snapshots.lock().unwrap().register(version);
+        KvStoreSnapshot {
+            handle: Arc::new(SnapshotHandle {
+                core: Arc::clone(core),
+                version,
+            }),
+        }
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn version(&self) -> u64 {
+        self.handle.version
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.handle.read_value(&key)
+    }
+
+    /// Takes the entries of the matching keys as of the snapshot a chunk at
+    /// a time, and reads their values as the iterator advances. The iterator
+    /// keeps the snapshot alive.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<ScanIter> {
+        let reverse = options.reverse;
+        let chunk_handle = Arc::clone(&self.handle);
+        let handle = Arc::clone(&self.handle);
+        Ok(Box::new(ChunkedScan::new(
+            key_range(range),
+            options,
+            move |range, size| chunk_handle.entries(range, reverse, size),
+            move |key: &[u8], cmd_pos| handle.read_value_at(key, cmd_pos),
+        )))
+    }
+}
+
+impl SnapshotHandle {
+    /// Returns the entry of `key` as of the snapshot.
+    fn entry(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        // RwLock: kv_index
+        let kv_index = self.core.kv_index.read().unwrap();
+        let snapshots = self.core.snapshots.lock().unwrap();
+        match snapshots.lookup(key, self.version) {
+            Some(old_pos) => Ok(old_pos),
+            None => kv_index.get(key),
+        }
+    }
+
+    /// Returns the entries as of the snapshot of the keys within `range`,
+    /// from the greatest key if `reverse` is set, out of the first `size`
+    /// keys of the index and the first `size` keys of the history.
+    ///
+    /// A key past the end of a full chunk of one may be missing from the
+    /// other, so the chunk ends at the first of their ends.
+    fn entries(&self, range: KeyRange, reverse: bool, size: usize) -> Result<Chunk> {
+        // RwLock: kv_index
+        let kv_index = self.core.kv_index.read().unwrap();
+        let snapshots = self.core.snapshots.lock().unwrap();
+        let current = kv_index.entries(range.clone(), reverse, size)?;
+        let replaced = snapshots.history.range(range).map(|(key, _)| key);
+        let replaced: Vec<&Vec<u8>> = match reverse {
+            true => replaced.rev().take(size).collect(),
+            false => replaced.take(size).collect(),
+        };
+
+        let current_end = current
+            .last()
+            .filter(|_| current.len() == size)
+            .map(|(key, _)| key);
+        let replaced_end = replaced.last().filter(|_| replaced.len() == size).copied();
+        let last_key = match (current_end, replaced_end) {
+            (Some(a), Some(b)) if reverse => Some(a.max(b)),
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+        .cloned();
+        let covered = |key: &Vec<u8>| match &last_key {
+            Some(last_key) if reverse => key >= last_key,
+            Some(last_key) => key <= last_key,
+            None => true,
+        };
+
+        let mut entries = BTreeMap::new();
+        for (key, cmd_pos) in current {
+            if covered(&key) && snapshots.lookup(&key, self.version).is_none() {
+                entries.insert(key, cmd_pos);
+            }
+        }
+        for key in replaced.into_iter().filter(|key| covered(key)) {
+            if let Some(Some(old_pos)) = snapshots.lookup(key, self.version) {
+                entries.insert(key.clone(), old_pos);
+            }
+        }
+        let entries = match reverse {
+            true => entries.into_iter().rev().collect(),
+            false => entries.into_iter().collect(),
+        };
+        Ok((entries, last_key))
+    }
+
+    fn read_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.entry(key)? {
+            Some(cmd_pos) => self.read_value_at(key, cmd_pos),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the value of `key`, found at `cmd_pos` as of the snapshot.
+    fn read_value_at(&self, key: &[u8], mut cmd_pos: CommandPos) -> Result<Option<Vec<u8>>> {
+        loop {
+            if is_expired(cmd_pos.expires_at, now_millis()) {
+                return Ok(None);
+            }
+            let result = self.core.read_from_log(cmd_pos);
+            // A compaction moved the entry, which the index still had, and
+            // removed its log file in the meantime. Look it up again.
+            if let Err(KvsError::Io(ref err)) = result {
+                let current = self.entry(key)?;
+                if err.kind() == io::ErrorKind::NotFound && current != Some(cmd_pos) {
+                    match current {
+                        Some(new_pos) => {
+                            cmd_pos = new_pos;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+            }
+            return result.map(|record| record.map(|record| record.value));
+        }
+    }
+}
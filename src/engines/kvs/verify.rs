@@ -0,0 +1,290 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use super::compaction::{recover_compactions, LogStats};
+use super::hint::read_hint;
+use super::keydir::KeyDir;
+use super::record::{Record, RecordType};
+use super::{
+    has_json_logs, load, load_logs, log_path, read_exact_at, sorted_gens_with_extension,
+    CommandPos, KvStore, KvStoreOptions,
+};
+use crate::engines::expiry::{is_expired, now_millis};
+use crate::engines::lock::DirLock;
+use crate::{KvsError, Result};
+
+/// Result of `KvStore::verify`.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Number of logs walked.
+    pub logs: u64,
+    /// Number of hint files checked against their logs.
+    pub hints: u64,
+    /// Number of keys the logs leave set.
+    pub keys: u64,
+    /// Bytes of the valid records of the logs.
+    pub total_bytes: u64,
+    /// Bytes of the valid records that are overwritten, removed or expired.
+    pub stale_bytes: u64,
+    /// Whether the lock of the directory could not be taken, as another
+    /// process may have the store open for writing, in which case a damaged
+    /// tail of the newest log is taken for a write in progress.
+    pub live: bool,
+    /// What was found wrong, in the order of the logs.
+    pub problems: Vec<Problem>,
+}
+
+/// Something wrong with the files of a store, found by `KvStore::verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The records of a log stop being valid at `offset`, as the next one
+    /// is cut short, fails its checksum or is not framed as expected.
+    DamagedLog {
+        /// generation of the log
+        gen: u64,
+        /// offset of the first invalid record
+        offset: u64,
+        /// what is wrong with it
+        reason: String,
+    },
+    /// The hint file of a log can't be read, or is longer than its log.
+    DamagedHint {
+        /// generation of the log
+        gen: u64,
+    },
+    /// A hint file lists a key more than once.
+    DuplicateEntry {
+        /// generation of the log of the hint file
+        gen: u64,
+        /// the key
+        key: Vec<u8>,
+    },
+    /// An entry of a hint file points at no set record of its key.
+    DanglingEntry {
+        /// generation of the log of the hint file
+        gen: u64,
+        /// the key
+        key: Vec<u8>,
+        /// offset the entry points at
+        pos: u64,
+    },
+    /// The index loaded with the hint files does not have the location the
+    /// index rebuilt from the records of the logs has for a key.
+    IndexMismatch {
+        /// the key
+        key: Vec<u8>,
+    },
+}
+
+impl VerifyReport {
+    /// Share of the bytes of the logs that are stale, 0 for empty logs.
+    pub fn stale_ratio(&self) -> f64 {
+        match self.total_bytes {
+            0 => 0.0,
+            total => self.stale_bytes as f64 / total as f64,
+        }
+    }
+
+    /// Whether no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::DamagedLog {
+                gen,
+                offset,
+                reason,
+            } => write!(f, "log {} is damaged at offset {}: {}", gen, offset, reason),
+            Problem::DamagedHint { gen } => {
+                write!(f, "hint file of log {} is damaged or does not match", gen)
+            }
+            Problem::DuplicateEntry { gen, key } => write!(
+                f,
+                "hint file of log {} lists key {:?} more than once",
+                gen,
+                String::from_utf8_lossy(key)
+            ),
+            Problem::DanglingEntry { gen, key, pos } => write!(
+                f,
+                "hint file of log {} points key {:?} at offset {}, where it has no record",
+                gen,
+                String::from_utf8_lossy(key),
+                pos
+            ),
+            Problem::IndexMismatch { key } => write!(
+                f,
+                "index loaded with the hint files differs from the logs for key {:?}",
+                String::from_utf8_lossy(key)
+            ),
+        }
+    }
+}
+
+impl KvStore {
+    /// Checks the files of the store in `path` without changing them.
+    ///
+    /// Every record of every log is read and its framing and checksum
+    /// checked, which also rebuilds the index from the records alone. Each
+    /// hint file must list every key once, and point each to a record of
+    /// that key. The index loaded with the hint files, as `open` does, must
+    /// match the rebuilt one. The key directory file of a bounded index is
+    /// not checked, `open` rebuilds it if it does not match the logs.
+    ///
+    /// The directory is locked while it is checked if its lock file can be
+    /// locked, which is neither created nor written to, so that a store on a
+    /// read-only mount can be checked. Otherwise the store is taken to be
+    /// open for writing in another process, whose writes in progress at the
+    /// end of the newest log are not reported, and whose compactions may make
+    /// the check fail with an error.
+    ///
+    /// Errors are returned for a directory that can't be checked, problems
+    /// with its files are listed in the report.
+    pub fn verify(path: impl Into<PathBuf>) -> Result<VerifyReport> {
+        let path = path.into();
+        if has_json_logs(&path)? {
+            return Err(KvsError::StringError(format!(
+                "the logs in {} need to be converted by opening the store for writing",
+                path.display()
+            )));
+        }
+        let lock = DirLock::acquire_existing(&path);
+        let mut report = VerifyReport {
+            live: lock.is_none(),
+            ..VerifyReport::default()
+        };
+
+        let (gens, hints) = recover_compactions(&path, true)?;
+        let mut rebuilt = KeyDir::default();
+        let mut stats = LogStats::default();
+        for &gen in &gens {
+            let replayed = load(&path, gen, None, None, &mut rebuilt, &mut stats)?;
+            report.logs += 1;
+            let in_progress = report.live && Some(&gen) == gens.last();
+            if replayed.damaged && !in_progress {
+                report.problems.push(Problem::DamagedLog {
+                    gen,
+                    offset: replayed.end,
+                    reason: damage_reason(&path, gen, replayed.end)?,
+                });
+            }
+        }
+        report.keys = rebuilt.key_count();
+        report.total_bytes = stats.total();
+        report.stale_bytes = stats.stale();
+
+        for gen in sorted_gens_with_extension(&path, "hint")? {
+            // Hint files of logs that are gone are left by compactions, and
+            // removed on the next open.
+            if gens.contains(&gen) {
+                report.hints += 1;
+                check_hint(&path, gen, &mut report)?;
+            }
+        }
+
+        let options = KvStoreOptions::default().read_only(true);
+        match load_logs(&path, &gens, hints, &options, KeyDir::default()) {
+            Ok(loaded) => compare_indexes(&rebuilt, &loaded.kv_index, &mut report),
+            // Logs damaged before their end fail to load, which is reported
+            // already.
+            Err(KvsError::CorruptedLog { .. }) => {}
+            Err(err) => return Err(err),
+        }
+        Ok(report)
+    }
+}
+
+/// Tells what is wrong with the record at `offset` of the log of `gen`,
+/// where its replay stopped.
+fn damage_reason(dir: &Path, gen: u64, offset: u64) -> Result<String> {
+    if offset == 0 {
+        return Ok("missing or unknown file header".to_owned());
+    }
+    let mut reader = BufReader::new(File::open(log_path(dir, gen))?);
+    reader.seek(SeekFrom::Start(offset))?;
+    Ok(match Record::read_from(&mut reader) {
+        Err(err) => err.to_string(),
+        Ok(Some((record, _))) if record.record_type == RecordType::BatchCommit => {
+            "commit marker outside of a write batch".to_owned()
+        }
+        Ok(Some(_)) => "write batch without its commit marker".to_owned(),
+        Ok(None) => "unexpected end of the log".to_owned(),
+    })
+}
+
+/// Checks that the hint file of the log of `gen` lists each key once, and
+/// points it to a set record of that key.
+fn check_hint(dir: &Path, gen: u64, report: &mut VerifyReport) -> Result<()> {
+    let log_len = fs::metadata(log_path(dir, gen))?.len();
+    let hint = match read_hint(dir, gen)? {
+        Some(hint) if hint.log_len <= log_len => hint,
+        _ => {
+            report.problems.push(Problem::DamagedHint { gen });
+            return Ok(());
+        }
+    };
+    let log = File::open(log_path(dir, gen))?;
+    let mut keys = HashSet::new();
+    for entry in hint.entries()? {
+        let entry = entry?;
+        if !keys.insert(entry.key.clone()) {
+            report.problems.push(Problem::DuplicateEntry {
+                gen,
+                key: entry.key.clone(),
+            });
+        }
+        let points_at_record = entry.pos.checked_add(entry.len).is_some_and(|end| {
+            end <= hint.log_len && {
+                let mut bytes = vec![0; entry.len as usize];
+                read_exact_at(&log, &mut bytes, entry.pos).is_ok()
+                    && Record::decode(&bytes).is_ok_and(|record| {
+                        record.record_type == RecordType::Set
+                            && record.key == entry.key
+                            && record.expires_at == entry.expires_at
+                            && record.version == entry.version
+                    })
+            }
+        });
+        if !points_at_record {
+            report.problems.push(Problem::DanglingEntry {
+                gen,
+                key: entry.key,
+                pos: entry.pos,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reports the keys for which `loaded` differs from `rebuilt`. Keys that
+/// expired between the two loads are skipped.
+fn compare_indexes(rebuilt: &KeyDir, loaded: &KeyDir, report: &mut VerifyReport) {
+    let (rebuilt, loaded) = match (rebuilt, loaded) {
+        (KeyDir::Memory(rebuilt), KeyDir::Memory(loaded)) => (rebuilt, loaded),
+        _ => return,
+    };
+    let now = now_millis();
+    let mut positions: BTreeMap<&[u8], (Option<CommandPos>, Option<CommandPos>)> = BTreeMap::new();
+    for (key, &cmd_pos) in rebuilt {
+        positions.entry(key).or_default().0 = Some(cmd_pos);
+    }
+    for (key, &cmd_pos) in loaded {
+        positions.entry(key).or_default().1 = Some(cmd_pos);
+    }
+    for (key, (rebuilt_pos, loaded_pos)) in positions {
+        let expired = rebuilt_pos
+            .or(loaded_pos)
+            .is_some_and(|cmd_pos| is_expired(cmd_pos.expires_at, now));
+        if rebuilt_pos != loaded_pos && !expired {
+            report
+                .problems
+                .push(Problem::IndexMismatch { key: key.to_vec() });
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use fs2::FileExt;
+
+use crate::{KvsError, Result};
+
+/// Name of the lock file in a data directory.
+pub const LOCK_FILE_NAME: &str = "kvs.lock";
+
+/// Exclusive advisory lock on a data directory, held by the process that has
+/// the directory open for writing.
+///
+/// The lock file holds the PID of its owner. The lock is released when the
+/// value is dropped, or by the OS if the process dies. The file itself is left
+/// in place.
+#[derive(Debug)]
+pub struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// Takes the lock on `dir`, which must exist.
+    ///
+    /// Fails with `KvsError::Locked` if another process holds it.
+    pub fn acquire(dir: &Path) -> Result<DirLock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE_NAME))?;
+
+        if let Err(err) = lock_exclusive(&file) {
+            if err.kind() != fs2::lock_contended_error().kind() {
+                return Err(err.into());
+            }
+            // The owner writes its PID right after taking the lock.
+            for _ in 0..10 {
+                if let Ok(Some(pid)) = read_pid(&mut file) {
+                    return Err(KvsError::Locked { pid });
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            return Err(err.into());
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        file.sync_data()?;
+        Ok(DirLock { file })
+    }
+
+    /// Takes the lock on `dir` without creating or writing to the lock file,
+    /// for a process that only reads the directory, which may be on a
+    /// read-only mount.
+    ///
+    /// Returns `None` if the lock can't be taken, whether another process
+    /// holds it or the lock file is missing or can't be opened.
+    pub fn acquire_existing(dir: &Path) -> Option<DirLock> {
+        let file = File::open(dir.join(LOCK_FILE_NAME)).ok()?;
+        lock_exclusive(&file).ok()?;
+        Some(DirLock { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// Takes an exclusive lock on `file` without blocking.
+///
+/// The lock may be about to be released, e.g. by the threads of a store of
+/// this process that are done with it, so contention is retried for a little
+/// while before it is reported.
+fn lock_exclusive(file: &File) -> io::Result<()> {
+    let mut attempts = 0;
+    loop {
+        match file.try_lock_exclusive() {
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() && attempts < 9 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            result => return result,
+        }
+    }
+}
+
+fn read_pid(file: &mut File) -> io::Result<Option<u32>> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut contents)?;
+    Ok(contents.trim().parse().ok())
+}
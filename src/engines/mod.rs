@@ -1,22 +1,256 @@
-use crate::Result;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{KvsError, Result};
+
+use self::scan::prefix_range;
+use self::transaction::MAX_ATTEMPTS;
 
 /// defines the storage interface called by KvsServer
+///
+/// Keys and values are arbitrary bytes. The string methods are a convenience
+/// layer on top of the byte methods.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Consistent view of the engine returned by `snapshot`.
+    type Snapshot: KvsSnapshot;
+
+    /// Set the value of a key to the given bytes.
+    /// Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Get the value of a key as bytes. If the key does not exist, return None.
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Remove a given key.
+    /// Return an error if the key does not exit or value is not read successfully.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Set the value of a key to the given bytes for `ttl`, after which the
+    /// key is gone as if it had been removed.
+    /// Return an error if the value is not written successfully.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Get the time left before a key expires, or None if it never does.
+    /// Return `KvsError::KeyNotFound` if the key does not exist.
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// Apply the writes of a batch all or nothing, in order.
+    /// Return an error if the batch is not written successfully, in which
+    /// case none of its writes is.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Apply the writes of a batch all or nothing, only if each key of
+    /// `expected` still has the version given with it, None meaning that the
+    /// key does not exist.
+    /// Return `KvsError::Conflict` if one does not, in which case none of the
+    /// writes is applied.
+    fn apply_batch_if(
+        &self,
+        batch: WriteBatch,
+        expected: Vec<(Vec<u8>, Option<u64>)>,
+    ) -> Result<()>;
+
+    /// Set the value of a key to `new`, or remove it if `new` is None, only
+    /// if its current value is `expected`, None meaning that the key does not
+    /// exist.
+    /// Return `KvsError::ConditionFailed` if it is not, in which case nothing
+    /// is written.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+
+    /// Set the value of a key only if it exists.
+    /// Return `KvsError::ConditionFailed` if it does not.
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Get the value of a key as bytes, with the version and the time of the
+    /// write that set it. If the key does not exist, return None.
+    /// Return an error if the value is not read successfully.
+    fn get_with_meta_bytes(&self, key: Vec<u8>) -> Result<Option<Versioned<Vec<u8>>>>;
+
+    /// Set the value of a key only if its current value was set by the write
+    /// of `version`.
+    /// Return `KvsError::ConditionFailed` if it was not, or if the key does
+    /// not exist.
+    fn set_bytes_if_version(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<()>;
+
+    /// Remove a key only if its current value was set by the write of
+    /// `version`.
+    /// Return `KvsError::ConditionFailed` if it was not, or if the key does
+    /// not exist.
+    fn remove_bytes_if_version(&self, key: Vec<u8>, version: u64) -> Result<()>;
+
+    /// Set the value of a key only if it does not exist.
+    /// Return `KvsError::ConditionFailed` if it does.
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
     /// Set the value of a string key to a string.
     /// Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
     /// Get the string value of a string key. If the key does not exist, return None.
-    /// Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Return an error if the value is not read successfully, or
+    /// `KvsError::Utf8` if it is not a valid string.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
 
     /// Remove a given string key.
     /// Return an error if the key does not exit or value is not read successfully.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Set the value of a string key to a string for `ttl`.
+    /// Return an error if the value is not written successfully.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Get the time left before a string key expires, or None if it never does.
+    /// Return `KvsError::KeyNotFound` if the key does not exist.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+
+    /// Get the string value of a string key, with the version and the time of
+    /// the write that set it. If the key does not exist, return None.
+    /// Return an error if the value is not read successfully, or
+    /// `KvsError::Utf8` if it is not a valid string.
+    fn get_with_meta(&self, key: String) -> Result<Option<Versioned<String>>> {
+        match self.get_with_meta_bytes(key.into_bytes())? {
+            Some(versioned) => Ok(Some(Versioned {
+                value: String::from_utf8(versioned.value)?,
+                version: versioned.version,
+                modified: versioned.modified,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the value of a string key only if its current value was set by the
+    /// write of `version`.
+    /// Return `KvsError::ConditionFailed` if it was not.
+    fn set_if_version(&self, key: String, value: String, version: u64) -> Result<()> {
+        self.set_bytes_if_version(key.into_bytes(), value.into_bytes(), version)
+    }
+
+    /// Remove a string key only if its current value was set by the write of
+    /// `version`.
+    /// Return `KvsError::ConditionFailed` if it was not.
+    fn remove_if_version(&self, key: String, version: u64) -> Result<()> {
+        self.remove_bytes_if_version(key.into_bytes(), version)
+    }
+
+    /// Set the value of a string key to `new`, or remove it if `new` is None,
+    /// only if its current value is `expected`, like `compare_and_swap_bytes`.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Set the value of a string key only if it exists.
+    /// Return `KvsError::ConditionFailed` if it does not.
+    fn set_if_present(&self, key: String, value: String) -> Result<()> {
+        self.set_if_present_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the value of a string key only if it does not exist.
+    /// Return `KvsError::ConditionFailed` if it does.
+    fn set_if_absent(&self, key: String, value: String) -> Result<()> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Returns the key/value pairs whose key falls within `range`, in key
+    /// order unless the options ask for the reverse.
+    /// Return an error if the index or a value cannot be read.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<ScanIter>;
+
+    /// Returns the key/value pairs whose key starts with `prefix`, like `scan`.
+    fn scan_prefix(&self, prefix: Vec<u8>, options: ScanOptions) -> Result<ScanIter> {
+        self.scan(prefix_range(prefix), options)
+    }
+
+    /// Writes a consistent copy of the data as of now to `dest_dir`, which
+    /// must not exist yet, while the engine keeps serving reads and writes.
+    ///
+    /// The copy is a data directory the engine can open like any other.
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()>;
+
+    /// Takes a view of the data as of now, which later writes don't change.
+    /// Return an error if the snapshot cannot be taken.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Returns the number of keys, the size of the data, and counters of the
+    /// compactions and the operations since the engine was opened.
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// Starts a transaction, whose writes are applied when it commits if the
+    /// keys it read have not changed.
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+    /// Runs `f` in a transaction and commits it, running it again in a new
+    /// transaction as long as it conflicts with other writes, up to a limit.
+    /// Return the result of `f`, the first error other than a conflict, or
+    /// `KvsError::Conflict` once the limit is reached.
+    fn transaction<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction<Self>) -> Result<T>,
+    {
+        for _ in 0..MAX_ATTEMPTS {
+            let mut tx = self.begin();
+            let result = f(&mut tx).and_then(|value| tx.commit().map(|()| value));
+            match result {
+                Err(KvsError::Conflict) => continue,
+                result => return result,
+            }
+        }
+        Err(KvsError::Conflict)
+    }
 }
 
+mod batch;
+mod checkpoint;
+mod condition;
+mod expiry;
 mod kvs;
+mod lock;
+mod scan;
 mod sled;
+mod snapshot;
+mod stats;
+mod sync;
+mod transaction;
+mod versioned;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::batch::WriteBatch;
+pub use self::kvs::{CacheStats, KvStore, KvStoreOptions, KvStoreSnapshot, Problem, VerifyReport};
+pub use self::scan::{ScanIter, ScanOptions};
+pub use self::sled::{SledKvsEngine, SledOptions, SledSnapshot};
+pub use self::snapshot::KvsSnapshot;
+pub use self::stats::EngineStats;
+pub use self::sync::SyncPolicy;
+pub use self::transaction::Transaction;
+pub use self::versioned::Versioned;
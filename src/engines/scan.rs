@@ -0,0 +1,80 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::Result;
+
+/// Iterator over the key/value pairs found by a scan, in key order or in
+/// reverse key order.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// Options of a scan.
+///
+/// To page through a keyspace, scan with a limit and start the next scan after
+/// the last key of the page.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result, ScanOptions};
+/// # use std::ops::Bound;
+/// # fn try_main(store: KvStore) -> Result<()> {
+/// let page: Vec<_> = store
+///     .scan_prefix(b"user/".to_vec(), ScanOptions::default().limit(100))?
+///     .collect::<Result<_>>()?;
+/// if let Some((last_key, _)) = page.last() {
+///     let range = (Bound::Excluded(last_key.clone()), Bound::Unbounded);
+///     let next_page = store.scan(range, ScanOptions::default().limit(100))?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanOptions {
+    pub(crate) limit: Option<usize>,
+    pub(crate) reverse: bool,
+}
+
+impl ScanOptions {
+    /// Returns at most `limit` pairs.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Returns the pairs from the greatest key to the smallest one.
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+}
+
+/// Owned bounds of a scan.
+pub(crate) type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+pub(crate) fn key_range(range: impl RangeBounds<Vec<u8>>) -> KeyRange {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+/// Returns the range of the keys starting with `prefix`.
+pub(crate) fn prefix_range(prefix: Vec<u8>) -> KeyRange {
+    let mut end = prefix.clone();
+    // The first key after the prefix is the prefix with its last byte that is
+    // not 0xff incremented.
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
+}
+
+/// Whether no key can fall within `range`.
+///
+/// Ordered maps panic on such ranges instead of returning nothing.
+pub(crate) fn is_empty_range(range: &KeyRange) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
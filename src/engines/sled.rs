@@ -1,42 +1,554 @@
-use std::path::PathBuf;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use crate::{KvsEngine, KvsError, Result};
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use log::error;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, Transactional, TransactionalTree,
+};
+use tempfile::TempDir;
+
+use super::batch::BatchOp;
+use super::checkpoint::create_checkpoint;
+use super::condition::Condition;
+use super::expiry::{expiry_after, is_expired, now_millis, time_left};
+use super::lock::DirLock;
+use super::scan::{is_empty_range, key_range, KeyRange};
+use super::stats::OpCounters;
+use crate::{
+    EngineStats, KvsEngine, KvsError, KvsSnapshot, Result, ScanIter, ScanOptions, SyncPolicy,
+    Versioned, WriteBatch,
+};
+
+/// Name of the tree holding the expiry of the keys that have one, in
+/// milliseconds since the Unix epoch, big-endian.
+const EXPIRY_TREE: &str = "kvs-expiry";
+
+/// Name of the tree holding the version of the write that set each key,
+/// followed by the time it was made in milliseconds since the Unix epoch,
+/// both big-endian.
+const META_TREE: &str = "kvs-meta";
+
+/// Name of the tree holding the version base of the database, under
+/// `VERSION_BASE_KEY`.
+const SEQUENCE_TREE: &str = "kvs-sequence";
+
+const VERSION_BASE_KEY: &[u8] = b"base";
+
+/// Name of the tree holding, while snapshots are open, the states of the keys
+/// that writes replaced, see `SledSnapshot`.
+const HISTORY_TREE: &str = "kvs-history";
+
+/// Default interval between two sweeps of the expired keys.
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 /// implements KvsEngine for the sled storage engine.
+///
+/// Expiries are kept in a separate tree and written in the same transaction
+/// as the values. Expired keys are hidden right away and removed by a
+/// background thread.
+///
+/// Versions are IDs from `sled::Db::generate_id`, which are unique and only
+/// grow, also across crashes, without writes contending for a counter. They
+/// are offset by a base that checkpoints record, since they do not carry the
+/// IDs over.
 #[derive(Debug, Clone)]
 pub struct SledKvsEngine {
     sled_db: sled::Db,
+    expiry: sled::Tree,
+    meta: sled::Tree,
+    history: sled::Tree,
+    version_base: u64,
+    /// Versions the open snapshots read at, with how many are open at each.
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,
+    sync_policy: SyncPolicy,
+    unsynced_bytes: Arc<AtomicU64>,
+    ops: Arc<OpCounters>,
+    read_only: bool,
+    /// Shared by writes and held exclusively by checkpoints and while a
+    /// snapshot is registered, so that they see no write half done.
+    write_gate: Arc<RwLock<()>>,
+    /// Only held so that the thread stops with the last clone. `None` if the
+    /// engine is read-only.
+    _sweeper: Option<Arc<Sweeper>>,
+    /// Held until the last clone is dropped. `None` if the engine is
+    /// read-only.
+    _lock: Option<Arc<DirLock>>,
+    /// The copy of the database a read-only engine reads, removed with the
+    /// last clone. Declared last so that sled closes it first.
+    _copy: Option<Arc<TempDir>>,
+}
+
+/// Options used to open a `SledKvsEngine`.
+#[derive(Debug, Clone)]
+pub struct SledOptions {
+    sync_policy: SyncPolicy,
+    read_only: bool,
+    sweep_interval: Duration,
+}
+
+impl Default for SledOptions {
+    fn default() -> Self {
+        SledOptions {
+            sync_policy: SyncPolicy::Always,
+            read_only: false,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+        }
+    }
+}
+
+impl SledOptions {
+    /// Sets when writes are flushed to disk. Defaults to `SyncPolicy::Always`.
+    ///
+    /// sled also flushes in the background every 500 milliseconds, or at the
+    /// interval of `SyncPolicy::EveryMillis`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Opens the engine read-only. Writes fail with `KvsError::ReadOnly`.
+    ///
+    /// sled always writes to the files it opens and locks them, so the engine
+    /// reads a copy of the database made in a temporary directory, and
+    /// neither locks nor changes the directory. Opening takes as long as
+    /// copying the database, and the engine does not see the writes made
+    /// after it. A copy made while another process writes to the database
+    /// may miss its latest writes, or fail to open.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets how often expired keys are removed from the database. They are
+    /// hidden as soon as they expire either way. Defaults to 1 second.
+    pub fn sweep_interval(mut self, sweep_interval: Duration) -> Self {
+        self.sweep_interval = sweep_interval;
+        self
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
-    /// Set the value of a string key to a string.
+    type Snapshot = SledSnapshot;
+
+    /// Set the value of a key to the given bytes.
     /// Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.sled_db.insert(key, value.as_bytes()).unwrap();
-        self.sled_db.flush().unwrap();
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(&key, None, Some(&value), None)?;
         Ok(())
     }
 
-    /// Get the string value of a string key.
+    /// Get the value of a key as bytes.
     /// If the key does not exist, return None.
     /// Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let get_result = self.sled_db.get(key).unwrap();
-        match get_result {
-            Some(iv) => Ok(Some(std::str::from_utf8(iv.as_ref()).unwrap().to_string())),
-            None => Ok(None),
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.ops.read();
+        match self.sled_db.get(&key)? {
+            Some(value) if !self.is_expired(&key)? => Ok(Some(value.to_vec())),
+            _ => Ok(None),
         }
     }
 
     /// Remove a given key.
     /// Return an error if the key does not exist or is not removed successfully.
-    fn remove(&self, key: String) -> Result<()> {
-        let rm_result = self.sled_db.remove(key).unwrap();
-        self.sled_db.flush().unwrap();
-        match rm_result {
-            Some(_) => Ok(()),
-            None => Err(KvsError::KeyNotFound),
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        match self.write(&key, None, None, None)? {
+            true => Ok(()),
+            false => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Set the value of a key to the given bytes for `ttl`.
+    /// Return an error if the value is not written successfully.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(&key, None, Some(&value), Some(expiry_after(ttl)))?;
+        Ok(())
+    }
+
+    /// Applies the batch as a `sled::Batch` on the values, one on the
+    /// expiries and one on the versions, in a single transaction.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.apply_batch_if(batch, Vec::new())
+    }
+
+    /// Checks the versions in the transaction that applies the batch.
+    fn apply_batch_if(
+        &self,
+        batch: WriteBatch,
+        expected: Vec<(Vec<u8>, Option<u64>)>,
+    ) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let now = now_millis();
+        let mut data_batch = sled::Batch::default();
+        let mut expiry_batch = sled::Batch::default();
+        // Versions are only known in the transaction.
+        let mut meta_keys = Vec::with_capacity(batch.ops.len());
+        let mut written = 0;
+        for op in batch.ops {
+            match op {
+                BatchOp::Set { key, value, ttl_ms } => {
+                    written += key.len() + value.len();
+                    match ttl_ms {
+                        Some(ttl_ms) => {
+                            let expires_at = expiry_after(Duration::from_millis(ttl_ms));
+                            expiry_batch.insert(key.as_slice(), &expires_at.to_be_bytes());
+                        }
+                        None => expiry_batch.remove(key.as_slice()),
+                    }
+                    meta_keys.push((key.clone(), true));
+                    data_batch.insert(key, value);
+                }
+                BatchOp::Rm { key } => {
+                    written += key.len();
+                    expiry_batch.remove(key.as_slice());
+                    meta_keys.push((key.clone(), false));
+                    data_batch.remove(key);
+                }
+            }
         }
+        {
+            let _gate = self.write_gate.read().unwrap();
+            let keep_history = self.has_snapshots();
+            (&*self.sled_db, &self.expiry, &self.meta, &self.history).transaction(
+                |(data, expiry, meta, history)| -> ConflictableTransactionResult<(), KvsError> {
+                    for (key, version) in &expected {
+                        let expires_at = expiry.get(key)?.as_deref().and_then(decode_u64);
+                        let current = match data.get(key)? {
+                            Some(_) if !is_expired(expires_at, now) => {
+                                Some(stored_version(meta, key)?)
+                            }
+                            _ => None,
+                        };
+                        if current != *version {
+                            return Err(ConflictableTransactionError::Abort(KvsError::Conflict));
+                        }
+                    }
+                    let mut meta_batch = sled::Batch::default();
+                    for (key, is_set) in &meta_keys {
+                        let version = self.version_for(data.generate_id()?);
+                        if keep_history {
+                            let expires_at = expiry.get(key)?.as_deref().and_then(decode_u64);
+                            let value = data.get(key)?.map(|value| (value.to_vec(), expires_at));
+                            push_past_state(history, key, PastState::new(version, value))?;
+                        }
+                        match is_set {
+                            true => meta_batch.insert(key.as_slice(), &encode_meta(version, now)),
+                            false => meta_batch.remove(key.as_slice()),
+                        }
+                    }
+                    data.apply_batch(&data_batch)?;
+                    expiry.apply_batch(&expiry_batch)?;
+                    meta.apply_batch(&meta_batch)?;
+                    Ok(())
+                },
+            )?;
+        }
+        self.ops.write();
+        self.after_write(written as u64)
+    }
+
+    /// Checks the current value in the transaction that writes the new one,
+    /// rather than with `sled::Tree::compare_and_swap`, so that the expiry
+    /// is checked and cleared along with it.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let condition = Condition::Equals(expected);
+        self.write(&key, Some(&condition), new.as_deref(), None)?;
+        Ok(())
+    }
+
+    /// Checks that the key exists in the transaction that writes the value.
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(&key, Some(&Condition::Present), Some(&value), None)?;
+        Ok(())
+    }
+
+    /// Reads the version and the time of modification from the version tree.
+    /// Reads the value and its version in one transaction, so that the
+    /// version is that of the value even while it is being written.
+    fn get_with_meta_bytes(&self, key: Vec<u8>) -> Result<Option<Versioned<Vec<u8>>>> {
+        self.ops.read();
+        let now = now_millis();
+        let versioned = (&*self.sled_db, &self.expiry, &self.meta).transaction(
+            |(data, expiry, meta)| -> ConflictableTransactionResult<_, KvsError> {
+                let value = match data.get(&key)? {
+                    Some(value) => value,
+                    None => return Ok(None),
+                };
+                let expires_at = expiry.get(&key)?.as_deref().and_then(decode_u64);
+                if is_expired(expires_at, now) {
+                    return Ok(None);
+                }
+                let (version, modified_at) = meta
+                    .get(&key)?
+                    .as_deref()
+                    .and_then(decode_meta)
+                    .unwrap_or((0, 0));
+                Ok(Some(Versioned::new(value.to_vec(), version, modified_at)))
+            },
+        )?;
+        Ok(versioned)
+    }
+
+    /// Checks the version in the transaction that writes the value.
+    fn set_bytes_if_version(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<()> {
+        self.write(&key, Some(&Condition::Version(version)), Some(&value), None)?;
+        Ok(())
+    }
+
+    /// Checks the version in the transaction that removes the key.
+    fn remove_bytes_if_version(&self, key: Vec<u8>, version: u64) -> Result<()> {
+        self.write(&key, Some(&Condition::Version(version)), None, None)?;
+        Ok(())
+    }
+
+    /// Get the time left before a key expires, from the expiry tree.
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.ops.read();
+        if !self.sled_db.contains_key(&key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        match self.expiry.get(&key)?.as_deref().and_then(decode_u64) {
+            Some(expires_at) if is_expired(Some(expires_at), now_millis()) => {
+                Err(KvsError::KeyNotFound)
+            }
+            expires_at => Ok(expires_at.map(time_left)),
+        }
+    }
+
+    /// Iterates over `sled::Db::range`.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<ScanIter> {
+        self.ops.read();
+        Ok(scan_tree(
+            &self.sled_db,
+            &self.expiry,
+            key_range(range),
+            options,
+        ))
+    }
+
+    /// Imports an export of the database into a new one in `dest_dir`.
+    /// Writes wait until the export is complete.
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        create_checkpoint(dest_dir, |dest_dir| {
+            let dest_db = sled::open(sled_db_path(dest_dir))?;
+            let next_version = {
+                let _gate = self.write_gate.write().unwrap();
+                dest_db.import(self.sled_db.export());
+                self.version_for(self.sled_db.generate_id()?)
+            };
+            // The IDs of the copy start over, so its versions go on from the
+            // next one of this database.
+            dest_db
+                .open_tree(SEQUENCE_TREE)?
+                .insert(VERSION_BASE_KEY, &next_version.to_be_bytes())?;
+            dest_db.flush()?;
+            Ok(())
+        })
+    }
+
+    /// Registers the version of the snapshot, from which on writes keep the
+    /// states they replace. Writes only wait for it to be registered.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let version = {
+            let _gate = self.write_gate.write().unwrap();
+            let version = self.version_for(self.sled_db.generate_id()?) - 1;
+            *self.snapshots.lock().unwrap().entry(version).or_insert(0) += 1;
+            version
+        };
+        Ok(SledSnapshot {
+            handle: Arc::new(SnapshotHandle {
+                engine: self.clone(),
+                version,
+            }),
+        })
+    }
+
+    /// Counts the keys by walking the tree of the values, including the
+    /// expired keys the sweeper has not removed yet, and takes the size of
+    /// the database files from sled. sled compacts its files on its own and
+    /// does not say how much of them is stale, so `stale_bytes` and
+    /// `compactions` stay 0.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.sled_db.len() as u64,
+            total_bytes: self.sled_db.size_on_disk()?,
+            stale_bytes: 0,
+            compactions: 0,
+            last_compaction: None,
+            reads: self.ops.reads(),
+            writes: self.ops.writes(),
+        })
+    }
+}
+
+/// A view of a `SledKvsEngine` as of the moment it was taken, returned by
+/// `KvsEngine::snapshot`.
+///
+/// sled keeps no older versions, so while snapshots are open, writes also
+/// keep the state each key had before them in a history tree, along with
+/// their version. A snapshot reads the state replaced by the first write
+/// after it, if any, and the current one otherwise.
+///
+/// The history grows with every write until the snapshots open when it was
+/// made are dropped, and dropping one walks the whole history to prune it.
+#[derive(Debug)]
+pub struct SledSnapshot {
+    handle: Arc<SnapshotHandle>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn version(&self) -> u64 {
+        self.handle.version
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.handle.get(&key)
+    }
+
+    /// Looks the keys up one at a time, in the database and in the history.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<ScanIter> {
+        Ok(Box::new(SnapshotScan {
+            handle: Arc::clone(&self.handle),
+            range: key_range(range),
+            reverse: options.reverse,
+            remaining: options.limit,
+        }))
+    }
+}
+
+/// The version a `SledSnapshot` and its scans read at, registered with the
+/// engine until they are all dropped.
+#[derive(Debug)]
+struct SnapshotHandle {
+    engine: SledKvsEngine,
+    version: u64,
+}
+
+impl SnapshotHandle {
+    /// Returns the value of `key` at the version of the snapshot.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let engine = &self.engine;
+        let value = (&*engine.sled_db, &engine.expiry, &engine.history).transaction(
+            |(data, expiry, history)| -> ConflictableTransactionResult<_, KvsError> {
+                let past = read_history(history, key)?
+                    .into_iter()
+                    .find(|state| state.replaced_by > self.version);
+                if let Some(state) = past {
+                    return Ok(state.value);
+                }
+                let value = match data.get(key)? {
+                    Some(value) => value.to_vec(),
+                    None => return Ok(None),
+                };
+                let expires_at = expiry.get(key)?.as_deref().and_then(decode_u64);
+                Ok(Some((value, expires_at)))
+            },
+        )?;
+        Ok(value
+            .filter(|&(_, expires_at)| !is_expired(expires_at, now_millis()))
+            .map(|(value, _)| value))
+    }
+
+    /// Returns the first key within `range`, or the last one if `reverse`,
+    /// that is either in the database or in the history.
+    ///
+    /// A key removed since the snapshot is moved to the history by the same
+    /// transaction, so looking in the database first misses none.
+    fn next_key(&self, range: &KeyRange, reverse: bool) -> Result<Option<Vec<u8>>> {
+        if is_empty_range(range) {
+            return Ok(None);
+        }
+        let in_data = first_key(&self.engine.sled_db, range, reverse)?;
+        let in_history = first_key(&self.engine.history, range, reverse)?;
+        Ok(match (in_data, in_history) {
+            (Some(a), Some(b)) if reverse => Some(a.max(b)),
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        })
+    }
+}
+
+impl Drop for SnapshotHandle {
+    fn drop(&mut self) {
+        let engine = &self.engine;
+        let oldest = {
+            let mut snapshots = engine.snapshots.lock().unwrap();
+            if let Entry::Occupied(mut open) = snapshots.entry(self.version) {
+                *open.get_mut() -= 1;
+                if *open.get() == 0 {
+                    open.remove();
+                }
+            }
+            match snapshots.keys().next() {
+                Some(&oldest) => Ok(oldest),
+                // Snapshots taken from now on read at a later version.
+                None => engine
+                    .sled_db
+                    .generate_id()
+                    .map(|id| engine.version_for(id) - 1),
+            }
+        };
+        let pruned = oldest
+            .map_err(KvsError::from)
+            .and_then(|oldest| prune_history(&engine.history, oldest));
+        if let Err(err) = pruned {
+            error!("pruning the history of the snapshots failed: {}", err);
+        }
+    }
+}
+
+/// Iterator over the pairs a `SledSnapshot` sees, looking up the key after
+/// the last one at each step.
+struct SnapshotScan {
+    handle: Arc<SnapshotHandle>,
+    /// The part of the range left to scan.
+    range: KeyRange,
+    reverse: bool,
+    remaining: Option<usize>,
+}
+
+impl Iterator for SnapshotScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining != Some(0) {
+            let key = match self.handle.next_key(&self.range, self.reverse) {
+                Ok(key) => key?,
+                Err(err) => return Some(Err(err)),
+            };
+            let after = Bound::Excluded(key.clone());
+            match self.reverse {
+                true => self.range.1 = after,
+                false => self.range.0 = after,
+            }
+            match self.handle.get(&key) {
+                Ok(Some(value)) => {
+                    if let Some(remaining) = &mut self.remaining {
+                        *remaining -= 1;
+                    }
+                    return Some(Ok((key, value)));
+                }
+                // Absent or expired at the version of the snapshot.
+                Ok(None) => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        None
     }
 }
 
@@ -45,11 +557,491 @@ impl SledKvsEngine {
     ///
     /// This will create a new database file if the given one does not exist.
     pub fn open(db_path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
-        let mut path: PathBuf = db_path.into();
-        path.push("sled");
-        path.set_extension("db");
+        SledKvsEngine::open_with(db_path, SledOptions::default())
+    }
+
+    /// Opens a `SledKvsEngine` with the given path for reading only.
+    ///
+    /// The database must exist, and is read from a copy, see
+    /// `SledOptions::read_only`. Writes fail with `KvsError::ReadOnly`.
+    pub fn open_read_only(db_path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        SledKvsEngine::open_with(db_path, SledOptions::default().read_only(true))
+    }
+
+    /// Opens a `SledKvsEngine` with the given path and options.
+    ///
+    /// Unless the engine is read-only, the directory is locked against other
+    /// processes until the engine and all its clones are dropped. A directory
+    /// locked by another process is reported as `KvsError::Locked`.
+    pub fn open_with(db_path: impl Into<PathBuf>, options: SledOptions) -> Result<SledKvsEngine> {
+        let dir: PathBuf = db_path.into();
+        let mut path = sled_db_path(&dir);
+        let (lock, copy) = if options.read_only {
+            if !path.is_dir() {
+                return Err(KvsError::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no sled database in {}", dir.display()),
+                )));
+            }
+            let copy = TempDir::new()?;
+            copy_dir(&path, copy.path())?;
+            path = copy.path().to_owned();
+            (None, Some(Arc::new(copy)))
+        } else {
+            fs::create_dir_all(&dir)?;
+            (Some(Arc::new(DirLock::acquire(&dir)?)), None)
+        };
+
+        let mut config = sled::Config::new().path(&path);
+        // The other policies keep the background flusher of sled, so that
+        // writes reach the OS even if nothing else flushes them.
+        if let SyncPolicy::EveryMillis(millis) = options.sync_policy {
+            config = config.flush_every_ms(Some(millis));
+        }
+        let sled_db = config.open()?;
+        // Trees missing from a read-only copy are only created in the copy.
+        let expiry = sled_db.open_tree(EXPIRY_TREE)?;
+        let meta = sled_db.open_tree(META_TREE)?;
+        let history = sled_db.open_tree(HISTORY_TREE)?;
+        if !options.read_only {
+            // No snapshot outlives the engine.
+            history.clear()?;
+        }
+        let version_base = sled_db
+            .open_tree(SEQUENCE_TREE)?
+            .get(VERSION_BASE_KEY)?
+            .as_deref()
+            .and_then(decode_u64)
+            .unwrap_or(0);
+        let write_gate = Arc::new(RwLock::new(()));
+        let sweeper = if options.read_only {
+            None
+        } else {
+            Some(Arc::new(Sweeper::spawn(
+                (*sled_db).clone(),
+                expiry.clone(),
+                meta.clone(),
+                Arc::clone(&write_gate),
+                options.sweep_interval,
+            )?))
+        };
+
         Ok(SledKvsEngine {
-            sled_db: sled::open(&path).unwrap(),
+            sled_db,
+            expiry,
+            meta,
+            history,
+            version_base,
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            sync_policy: options.sync_policy,
+            unsynced_bytes: Arc::new(AtomicU64::new(0)),
+            ops: Arc::new(OpCounters::default()),
+            read_only: options.read_only,
+            write_gate,
+            _sweeper: sweeper,
+            _lock: lock,
+            _copy: copy,
+        })
+    }
+
+    /// Returns the version of a write that got `id` from `generate_id`,
+    /// greater than all the ones given out before it.
+    ///
+    /// Inside a transaction, the ID must come from one of its trees, since
+    /// `sled::Db::generate_id` waits for the transaction to end.
+    fn version_for(&self, id: u64) -> u64 {
+        self.version_base + id + 1
+    }
+
+    /// Writes `value` to `key` with its expiry and a new version, or removes
+    /// them all if `value` is `None`, in a single transaction. If a
+    /// `condition` is given, the key must meet it in the same transaction.
+    ///
+    /// Returns whether the key existed before, expired keys aside.
+    fn write(
+        &self,
+        key: &[u8],
+        condition: Option<&Condition>,
+        value: Option<&[u8]>,
+        expires_at: Option<u64>,
+    ) -> Result<bool> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let now = now_millis();
+        let existed = {
+            let _gate = self.write_gate.read().unwrap();
+            let keep_history = self.has_snapshots();
+            (&*self.sled_db, &self.expiry, &self.meta, &self.history).transaction(
+                |(data, expiry, meta, history)| -> ConflictableTransactionResult<bool, KvsError> {
+                    if let Some(condition) = condition {
+                        let current_expiry = expiry.get(key)?;
+                        let current = data.get(key)?.filter(|_| {
+                            !is_expired(current_expiry.as_deref().and_then(decode_u64), now)
+                        });
+                        let current_version = match current {
+                            Some(_) => stored_version(meta, key)?,
+                            None => 0,
+                        };
+                        let current = current.as_deref().map(|value| (current_version, value));
+                        if !condition.holds(current) {
+                            return Err(ConflictableTransactionError::Abort(
+                                KvsError::ConditionFailed,
+                            ));
+                        }
+                    }
+                    let version = self.version_for(data.generate_id()?);
+                    let old_value = match value {
+                        Some(value) => {
+                            meta.insert(key, &encode_meta(version, now))?;
+                            data.insert(key, value)?
+                        }
+                        None => {
+                            meta.remove(key)?;
+                            data.remove(key)?
+                        }
+                    };
+                    let old_expiry = match expires_at {
+                        Some(expires_at) => expiry.insert(key, &expires_at.to_be_bytes())?,
+                        None => expiry.remove(key)?,
+                    };
+                    let old_expiry = old_expiry.as_deref().and_then(decode_u64);
+                    let existed = old_value.is_some() && !is_expired(old_expiry, now);
+                    if keep_history {
+                        let old_value = old_value.map(|value| (value.to_vec(), old_expiry));
+                        push_past_state(history, key, PastState::new(version, old_value))?;
+                    }
+                    Ok(existed)
+                },
+            )?
+        };
+        // The removal of a missing key fails, and does not count.
+        if existed || value.is_some() || condition.is_some() {
+            self.ops.write();
+        }
+        let written = key.len() + value.map_or(0, <[u8]>::len);
+        self.after_write(written as u64)?;
+        Ok(existed)
+    }
+
+    /// Whether snapshots are open, which the writes must keep the states they
+    /// replace for.
+    fn has_snapshots(&self) -> bool {
+        !self.snapshots.lock().unwrap().is_empty()
+    }
+
+    /// Whether the value of `key` has expired.
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        let expires_at = self.expiry.get(key)?;
+        Ok(is_expired(
+            expires_at.as_deref().and_then(decode_u64),
+            now_millis(),
+        ))
+    }
+
+    /// Flushes `written` bytes of a write according to the sync policy.
+    fn after_write(&self, written: u64) -> Result<()> {
+        match self.sync_policy {
+            SyncPolicy::Always => {
+                self.sled_db.flush()?;
+            }
+            SyncPolicy::EveryBytes(bytes) => {
+                let unsynced = self.unsynced_bytes.fetch_add(written, Ordering::SeqCst) + written;
+                if unsynced >= bytes {
+                    self.unsynced_bytes.store(0, Ordering::SeqCst);
+                    self.sled_db.flush()?;
+                }
+            }
+            SyncPolicy::EveryMillis(_) | SyncPolicy::Os => {}
+        }
+        Ok(())
+    }
+}
+
+/// Handle to the thread removing the expired keys of a `SledKvsEngine` at a
+/// fixed interval.
+///
+/// Dropping it stops the thread.
+#[derive(Debug)]
+struct Sweeper {
+    tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    fn spawn(
+        data: sled::Tree,
+        expiry: sled::Tree,
+        meta: sled::Tree,
+        write_gate: Arc<RwLock<()>>,
+        interval: Duration,
+    ) -> Result<Sweeper> {
+        // Nothing is ever sent, dropping the sender stops the thread.
+        let (tx, rx) = channel::bounded::<()>(0);
+        let handle = thread::Builder::new()
+            .name("kvs-sweeper".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) | Ok(()) = rx.recv_timeout(interval) {
+                    if let Err(err) = sweep(&data, &expiry, &meta, &write_gate) {
+                        error!("removing expired keys failed: {}", err);
+                    }
+                }
+            })?;
+
+        Ok(Sweeper {
+            tx: Some(tx),
+            handle: Some(handle),
         })
     }
 }
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Removes the keys that have expired by now, unless they were written again
+/// in the meantime.
+///
+/// Their values are hidden from snapshots too, so no history is kept for them.
+fn sweep(
+    data: &sled::Tree,
+    expiry: &sled::Tree,
+    meta: &sled::Tree,
+    write_gate: &RwLock<()>,
+) -> Result<()> {
+    let now = now_millis();
+    for pair in expiry.iter() {
+        let (key, expires_at) = pair?;
+        if !is_expired(decode_u64(&expires_at), now) {
+            continue;
+        }
+        let _gate = write_gate.read().unwrap();
+        (data, expiry, meta).transaction(
+            |(data, expiry, meta)| -> ConflictableTransactionResult<(), KvsError> {
+                if expiry.get(&key)?.as_ref() == Some(&expires_at) {
+                    data.remove(&key)?;
+                    expiry.remove(&key)?;
+                    meta.remove(&key)?;
+                }
+                Ok(())
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Iterates over the pairs of `data` within `range`, leaving out those that
+/// have expired according to `expiry`.
+fn scan_tree(
+    data: &sled::Tree,
+    expiry: &sled::Tree,
+    range: KeyRange,
+    options: ScanOptions,
+) -> ScanIter {
+    if is_empty_range(&range) {
+        return Box::new(std::iter::empty());
+    }
+    let iter = data.range(range);
+    let iter: Box<dyn Iterator<Item = _> + Send> = if options.reverse {
+        Box::new(iter.rev())
+    } else {
+        Box::new(iter)
+    };
+    let expiry = expiry.clone();
+    let now = now_millis();
+    let iter = iter.filter_map(move |pair| {
+        let pair = pair.and_then(|(key, value)| {
+            let expires_at = expiry.get(&key)?;
+            Ok((key, value, expires_at))
+        });
+        match pair {
+            Ok((_, _, Some(expires_at))) if is_expired(decode_u64(&expires_at), now) => None,
+            Ok((key, value, _)) => Some(Ok((key.to_vec(), value.to_vec()))),
+            Err(err) => Some(Err(err.into())),
+        }
+    });
+    match options.limit {
+        Some(limit) => Box::new(iter.take(limit)),
+        None => Box::new(iter),
+    }
+}
+
+/// Returns the first key of `tree` within `range`, or the last one if
+/// `reverse`.
+fn first_key(tree: &sled::Tree, range: &KeyRange, reverse: bool) -> Result<Option<Vec<u8>>> {
+    let mut pairs = tree.range(range.clone());
+    let pair = match reverse {
+        true => pairs.next_back(),
+        false => pairs.next(),
+    };
+    Ok(pair.transpose()?.map(|(key, _)| key.to_vec()))
+}
+
+/// A state of a key replaced by a write, as kept in the history tree.
+#[derive(Debug)]
+struct PastState {
+    /// Version of the write that replaced it.
+    replaced_by: u64,
+    /// The value and the time it expires, `None` if the key was absent.
+    value: Option<(Vec<u8>, Option<u64>)>,
+}
+
+impl PastState {
+    fn new(replaced_by: u64, value: Option<(Vec<u8>, Option<u64>)>) -> PastState {
+        PastState { replaced_by, value }
+    }
+}
+
+/// Encodes the states of a key, oldest first. Each is the version that
+/// replaced it and a flag, followed if the key was present by the time its
+/// value expires (0 if never), the length of the value and the value,
+/// integers big-endian.
+fn encode_history(states: &[PastState]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for state in states {
+        bytes.extend_from_slice(&state.replaced_by.to_be_bytes());
+        match &state.value {
+            Some((value, expires_at)) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&expires_at.unwrap_or(0).to_be_bytes());
+                bytes.extend_from_slice(&(value.len() as u64).to_be_bytes());
+                bytes.extend_from_slice(value);
+            }
+            None => bytes.push(0),
+        }
+    }
+    bytes
+}
+
+fn decode_history(mut bytes: &[u8]) -> Option<Vec<PastState>> {
+    let mut states = Vec::new();
+    while !bytes.is_empty() {
+        let replaced_by = decode_u64(bytes.get(..8)?)?;
+        let value = match bytes.get(8)? {
+            0 => {
+                bytes = &bytes[9..];
+                None
+            }
+            1 => {
+                let expires_at = decode_u64(bytes.get(9..17)?)?;
+                let end = usize::try_from(decode_u64(bytes.get(17..25)?)?)
+                    .ok()?
+                    .checked_add(25)?;
+                let value = bytes.get(25..end)?.to_vec();
+                bytes = &bytes[end..];
+                Some((
+                    value,
+                    Some(expires_at).filter(|&expires_at| expires_at != 0),
+                ))
+            }
+            _ => return None,
+        };
+        states.push(PastState::new(replaced_by, value));
+    }
+    Some(states)
+}
+
+/// Returns the states `key` had before the writes kept in the history.
+fn read_history(
+    history: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Vec<PastState>, KvsError> {
+    match history.get(key)? {
+        Some(bytes) => decode_history(&bytes).ok_or_else(|| {
+            ConflictableTransactionError::Abort(KvsError::StringError(
+                "damaged snapshot history".to_owned(),
+            ))
+        }),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Appends the state `key` had before a write to its history.
+fn push_past_state(
+    history: &TransactionalTree,
+    key: &[u8],
+    state: PastState,
+) -> ConflictableTransactionResult<(), KvsError> {
+    let mut states = read_history(history, key)?;
+    states.push(state);
+    history.insert(key, encode_history(&states))?;
+    Ok(())
+}
+
+/// Removes the states replaced by the writes up to `version` from the
+/// history, since no open snapshot reads them any more.
+fn prune_history(history: &sled::Tree, version: u64) -> Result<()> {
+    for key in history.iter().keys() {
+        let key = key?;
+        history.transaction(|history| -> ConflictableTransactionResult<(), KvsError> {
+            let mut states = read_history(history, &key)?;
+            states.retain(|state| state.replaced_by > version);
+            match states.is_empty() {
+                true => history.remove(&key)?,
+                false => history.insert(&key, encode_history(&states))?,
+            };
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Decodes a big-endian integer, as stored in the expiry and version trees.
+fn decode_u64(bytes: &[u8]) -> Option<u64> {
+    bytes.try_into().ok().map(u64::from_be_bytes)
+}
+
+fn encode_meta(version: u64, modified_at: u64) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&version.to_be_bytes());
+    bytes[8..].copy_from_slice(&modified_at.to_be_bytes());
+    bytes
+}
+
+/// Decodes the version and the time of modification of a key.
+fn decode_meta(bytes: &[u8]) -> Option<(u64, u64)> {
+    if bytes.len() != 16 {
+        return None;
+    }
+    let (version, modified_at) = bytes.split_at(8);
+    Some((decode_u64(version)?, decode_u64(modified_at)?))
+}
+
+/// Returns the version of the value of `key`, 0 if it was written before keys
+/// had versions.
+fn stored_version(
+    meta: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<u64, KvsError> {
+    let meta = meta.get(key)?;
+    Ok(meta
+        .as_deref()
+        .and_then(decode_meta)
+        .map_or(0, |(version, _)| version))
+}
+
+/// Returns the path of the sled database in the data directory `dir`.
+fn sled_db_path(dir: &Path) -> PathBuf {
+    dir.join("sled.db")
+}
+
+/// Copies the files of the directory `src` into the existing `dest`, along
+/// with its subdirectories.
+fn copy_dir(src: &Path, dest: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let dest_path = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            fs::create_dir(&dest_path)?;
+            copy_dir(&entry.path(), &dest_path)?;
+        } else {
+            fs::copy(entry.path(), dest_path)?;
+        }
+    }
+    Ok(())
+}
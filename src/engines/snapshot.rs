@@ -0,0 +1,53 @@
+use std::ops::RangeBounds;
+
+use super::scan::prefix_range;
+use crate::{Result, ScanIter, ScanOptions};
+
+/// A consistent view of an engine as of the moment it was taken, returned by
+/// `KvsEngine::snapshot`.
+///
+/// Writes made after it was taken are not visible through it, however long
+/// it is kept. Values still expire in it at the time they expire in the
+/// engine.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, KvsSnapshot, Result, ScanOptions};
+/// # fn try_main(store: KvStore) -> Result<()> {
+/// let snapshot = store.snapshot()?;
+/// store.set("key".to_owned(), "new".to_owned())?;
+/// // Still the value from before the write, if any.
+/// let old = snapshot.get("key".to_owned())?;
+/// for pair in snapshot.scan(.., ScanOptions::default())? {
+///     let (key, value) = pair?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub trait KvsSnapshot: Send + 'static {
+    /// Version of the last write the snapshot sees.
+    fn version(&self) -> u64;
+
+    /// Get the value of a key as bytes when the snapshot was taken. If the
+    /// key did not exist, return None.
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the key/value pairs whose key falls within `range` when the
+    /// snapshot was taken, like `KvsEngine::scan`.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<ScanIter>;
+
+    /// Get the string value of a string key when the snapshot was taken.
+    /// Return `KvsError::Utf8` if it is not a valid string.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the key/value pairs whose key starts with `prefix` when the
+    /// snapshot was taken, like `scan`.
+    fn scan_prefix(&self, prefix: Vec<u8>, options: ScanOptions) -> Result<ScanIter> {
+        self.scan(prefix_range(prefix), options)
+    }
+}
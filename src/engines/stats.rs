@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Figures about the data and the activity of an engine, returned by
+/// `KvsEngine::stats`.
+///
+/// Counters start from zero when the engine is opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Number of keys in the index. Expired keys count until they are swept.
+    pub keys: u64,
+    /// Bytes taken by the data: the records of the logs for `KvStore`, the
+    /// database files for sled.
+    pub total_bytes: u64,
+    /// Bytes of the logs taken by records that were overwritten, removed or
+    /// have expired, which compactions reclaim. Always 0 for sled, which
+    /// does not tell.
+    pub stale_bytes: u64,
+    /// Compactions run to completion. Always 0 for sled, which compacts on
+    /// its own.
+    pub compactions: u64,
+    /// How long the last compaction took, if there was one.
+    pub last_compaction: Option<Duration>,
+    /// Reads served: gets, gets with metadata, TTL lookups and scans.
+    pub reads: u64,
+    /// Writes applied, a batch counting as one.
+    pub writes: u64,
+}
+
+/// Counts the reads and the writes of an engine.
+#[derive(Debug, Default)]
+pub(crate) struct OpCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+}
+
+impl OpCounters {
+    pub fn read(&self) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn write(&self) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::KvsError;
+
+/// When an engine forces its writes to disk.
+///
+/// Writes that are not synced yet can be lost on a power failure even though
+/// they were acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync before every write is acknowledged.
+    Always,
+    /// Sync in the background every given number of milliseconds.
+    EveryMillis(u64),
+    /// Sync once the given number of bytes has been written since the last sync.
+    EveryBytes(u64),
+    /// Never sync explicitly and leave it to the operating system.
+    Os,
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::EveryMillis(millis) => write!(f, "{}ms", millis),
+            SyncPolicy::EveryBytes(bytes) => write!(f, "{}bytes", bytes),
+            SyncPolicy::Os => write!(f, "os"),
+        }
+    }
+}
+
+/// Parses `always`, `os`, `<N>ms` or `<N>bytes`.
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_count = |count: &str| {
+            count
+                .parse::<u64>()
+                .ok()
+                .filter(|&count| count > 0)
+                .ok_or_else(|| KvsError::StringError(format!("invalid sync policy: {}", s)))
+        };
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "os" => Ok(SyncPolicy::Os),
+            _ => {
+                if let Some(millis) = s.strip_suffix("ms") {
+                    Ok(SyncPolicy::EveryMillis(parse_count(millis)?))
+                } else if let Some(bytes) = s.strip_suffix("bytes") {
+                    Ok(SyncPolicy::EveryBytes(parse_count(bytes)?))
+                } else {
+                    Err(KvsError::StringError(format!("invalid sync policy: {}", s)))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{KvsEngine, KvsError, Result, WriteBatch};
+
+/// How many times `KvsEngine::transaction` runs a transaction that keeps
+/// conflicting before it gives up.
+pub(crate) const MAX_ATTEMPTS: usize = 100;
+
+/// A read-write transaction over several keys, started by
+/// `KvsEngine::begin` or run by `KvsEngine::transaction`.
+///
+/// Reads go to the engine and remember the version of what they found.
+/// Writes are kept in the transaction, whose reads see them, until `commit`
+/// applies them as a single batch, only if none of the keys read has changed
+/// in the meantime.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main(store: KvStore) -> Result<()> {
+/// let balance = |value: Option<String>| value.map_or(0, |value| value.parse::<i64>().unwrap());
+/// store.transaction(|tx| {
+///     let alice = balance(tx.get("alice".to_owned())?);
+///     let bob = balance(tx.get("bob".to_owned())?);
+///     tx.set("alice".to_owned(), (alice - 10).to_string())?;
+///     tx.set("bob".to_owned(), (bob + 10).to_string())
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    /// Version of each key read, `None` if it did not exist.
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    /// Value of each key written, `None` if it was removed.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Transaction<E> {
+        Transaction {
+            engine,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Get the value of a key as bytes, as written by the transaction or
+    /// else as found in the engine. If the key does not exist, return None.
+    /// Return `KvsError::Conflict` if the key changed since the transaction
+    /// last read it.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let found = self.engine.get_with_meta_bytes(key.clone())?;
+        let version = found.as_ref().map(|versioned| versioned.version);
+        match self.reads.entry(key) {
+            Entry::Occupied(entry) if *entry.get() != version => return Err(KvsError::Conflict),
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(version);
+            }
+        }
+        Ok(found.map(|versioned| versioned.value))
+    }
+
+    /// Set the value of a key to the given bytes when the transaction
+    /// commits.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    /// Remove a given key when the transaction commits.
+    /// Return `KvsError::KeyNotFound` if the key does not exist.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Get the string value of a string key, like `get_bytes`.
+    /// Return `KvsError::Utf8` if it is not a valid string.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the value of a string key when the transaction commits.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Remove a given string key when the transaction commits.
+    /// Return `KvsError::KeyNotFound` if the key does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Applies the writes of the transaction all or nothing.
+    /// Return `KvsError::Conflict` if a key it read has changed since, in
+    /// which case nothing is written.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        self.engine
+            .apply_batch_if(batch, self.reads.into_iter().collect())
+    }
+}
@@ -0,0 +1,29 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A value along with the version and the time of the write that set it.
+///
+/// Every write gets a sequence number higher than those of the writes before
+/// it, so versions tell which of two values of a key is newer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned<V> {
+    /// the value
+    pub value: V,
+    /// Sequence number of the write. 0 for values written before keys had
+    /// versions.
+    pub version: u64,
+    /// When the write was made. The Unix epoch for values written before
+    /// keys had versions.
+    pub modified: SystemTime,
+}
+
+impl<V> Versioned<V> {
+    /// Builds a `Versioned` from a time of modification in milliseconds since
+    /// the Unix epoch.
+    pub(crate) fn new(value: V, version: u64, modified_at: u64) -> Versioned<V> {
+        Versioned {
+            value,
+            version,
+            modified: UNIX_EPOCH + Duration::from_millis(modified_at),
+        }
+    }
+}
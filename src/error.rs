@@ -1,5 +1,10 @@
+// `failure` derives its impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
+use sled::transaction::TransactionError;
 use std::io;
+use std::string::FromUtf8Error;
 
 /// Error type for kvs
 #[derive(Fail, Debug)]
@@ -8,11 +13,39 @@ pub enum KvsError {
     #[fail(display = "Key not found")]
     KeyNotFound,
 
+    /// A conditional write found the key in another state than it expected.
+    #[fail(display = "Condition failed")]
+    ConditionFailed,
+
+    /// A transaction read keys that other writes changed before it committed.
+    #[fail(display = "Transaction conflict")]
+    Conflict,
+
     /// Unexpected command type error.
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
 
+    /// A log record failed its framing or checksum checks.
+    #[fail(display = "Corrupted log {} at offset {}", gen, offset)]
+    CorruptedLog {
+        /// generation of the damaged log file
+        gen: u64,
+        /// offset of the damaged record in the file
+        offset: u64,
+    },
+
+    /// The data directory is open for writing in another process.
+    #[fail(display = "Data directory is locked by process {}", pid)]
+    Locked {
+        /// PID of the process holding the lock
+        pid: u32,
+    },
+
+    /// A write was attempted on an engine opened read-only.
+    #[fail(display = "Engine is opened read-only")]
+    ReadOnly,
+
     /// Unexpected config error.
     #[fail(display = "Unexpected config")]
     UnexpectedConfig,
@@ -25,6 +58,14 @@ pub enum KvsError {
     #[fail(display = "serde_json error: {}", _0)]
     Sered(#[cause] serde_json::Error),
 
+    /// Error from the sled engine
+    #[fail(display = "sled error: {}", _0)]
+    Sled(#[cause] sled::Error),
+
+    /// A value read through the string API is not valid UTF-8.
+    #[fail(display = "Value is not valid UTF-8: {}", _0)]
+    Utf8(#[cause] FromUtf8Error),
+
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
@@ -42,6 +83,27 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> KvsError {
+        KvsError::Sled(err)
+    }
+}
+
+impl From<TransactionError<KvsError>> for KvsError {
+    fn from(err: TransactionError<KvsError>) -> KvsError {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => KvsError::Sled(err),
+        }
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::Utf8(err)
+    }
+}
+
 /// A type alias for Result that includes your concrete error type,
 /// so that you don't need to type Result<T, YourErrorType> everywhere,
 /// but can simply type Result<T>.
@@ -3,8 +3,12 @@
 
 // pub use kv::KvStore;
 
-pub use client::Client;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use client::{Client, ClientTransaction};
+pub use engines::{
+    CacheStats, EngineStats, KvStore, KvStoreOptions, KvStoreSnapshot, KvsEngine, KvsSnapshot,
+    Problem, ScanIter, ScanOptions, SledKvsEngine, SledOptions, SledSnapshot, SyncPolicy,
+    Transaction, VerifyReport, Versioned, WriteBatch,
+};
 pub use error::{KvsError, Result};
 pub use server::Server;
 pub use thread_pool::ThreadPool;
@@ -1,16 +1,19 @@
 use log::info;
 use serde::Deserialize;
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 
 use crate::engines::KvsEngine;
-use crate::error::Result;
+use crate::error::{KvsError, Result};
 use crate::util::{Command, Response};
-use crate::ThreadPool;
+use crate::{ThreadPool, WriteBatch};
 
 /// a key-value store server
 pub struct Server<E: KvsEngine, P: ThreadPool> {
     engine: E,
     thread_pool: P,
+    checkpoint_root: Option<PathBuf>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
@@ -19,9 +22,19 @@ impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
         Ok(Server {
             engine,
             thread_pool,
+            checkpoint_root: None,
         })
     }
 
+    /// Lets clients write checkpoints to directories under `root`.
+    ///
+    /// Checkpoints are refused unless it is set, since they create
+    /// directories and fill them with the data on the server side.
+    pub fn checkpoint_dir(mut self, root: impl Into<PathBuf>) -> Self {
+        self.checkpoint_root = Some(root.into());
+        self
+    }
+
     /// listen and handle commands from cients.
     pub fn listen(&self, addr: SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(addr).unwrap();
@@ -29,24 +42,58 @@ impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
         // accept connections and process them serially
         for stream in listener.incoming() {
             let engine = self.engine.clone();
-            self.thread_pool
-                .spawn(move || handle_client(engine, stream.unwrap()).unwrap());
+            let checkpoint_root = self.checkpoint_root.clone();
+            self.thread_pool.spawn(move || {
+                handle_client(engine, checkpoint_root.as_deref(), stream.unwrap()).unwrap()
+            });
         }
         info!("stop listening...");
         Ok(())
     }
 }
 
-fn handle_client<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+fn handle_client<E: KvsEngine>(
+    engine: E,
+    checkpoint_root: Option<&Path>,
+    stream: TcpStream,
+) -> Result<()> {
     let cmd = {
         let mut de = serde_json::Deserializer::from_reader(&stream);
         Command::deserialize(&mut de).unwrap()
     };
 
     let res = match cmd {
-        Command::Set { key, value } => handle_set(engine, key, value),
+        Command::Set {
+            key,
+            value,
+            ttl_ms,
+            version: None,
+        } => handle_set(engine, key, value, ttl_ms),
+        Command::Set {
+            key,
+            value,
+            ttl_ms: None,
+            version: Some(version),
+        } => write_response(engine.set_bytes_if_version(key, value, version)),
+        Command::Set { .. } => Ok(Response::error(
+            "A TTL and a version cannot be given together",
+        )),
         Command::Get { key } => handle_get(engine, key),
-        Command::Rm { key } => handle_remove(engine, key),
+        Command::GetMeta { key } => handle_get_meta(engine, key),
+        Command::Rm { key, version: None } => handle_remove(engine, key),
+        Command::Rm {
+            key,
+            version: Some(version),
+        } => write_response(engine.remove_bytes_if_version(key, version)),
+        Command::Cas { key, expected, new } => handle_cas(engine, key, expected, new),
+        Command::SetIfAbsent { key, value } => handle_set_if(engine, key, value, false),
+        Command::SetIfPresent { key, value } => handle_set_if(engine, key, value, true),
+        Command::Ttl { key } => handle_ttl(engine, key),
+        Command::Batch { batch } => handle_batch(engine, batch),
+        Command::Checkpoint { dir } => handle_checkpoint(engine, checkpoint_root, dir),
+        Command::Stats => handle_stats(engine),
+        Command::Begin => return handle_transaction(engine, &stream),
+        Command::Commit | Command::Abort => Ok(Response::error("No transaction in progress")),
     }
     .unwrap();
 
@@ -56,50 +103,195 @@ fn handle_client<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
     Ok(())
 }
 
-fn handle_set<E: KvsEngine>(engine: E, key: String, value: String) -> Result<Response> {
-    let set_result = engine.set(key, value);
-    match set_result {
-        Ok(_) => Ok(Response {
-            res: true,
-            info: "".to_string(),
-        }),
-        Err(err) => Ok(Response {
-            res: false,
-            info: err.to_string(),
-        }),
+fn handle_set<E: KvsEngine>(
+    engine: E,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    ttl_ms: Option<u64>,
+) -> Result<Response> {
+    let set_result = match ttl_ms {
+        Some(ttl_ms) => engine.set_bytes_with_ttl(key, value, Duration::from_millis(ttl_ms)),
+        None => engine.set_bytes(key, value),
+    };
+    write_response(set_result)
+}
+
+fn handle_get<E: KvsEngine>(engine: E, key: Vec<u8>) -> Result<Response> {
+    let get_result = engine.get_bytes(key);
+    match get_result {
+        Ok(Some(v)) => Ok(Response::value(v)),
+        Ok(None) => Ok(Response::info("Key not found")),
+        Err(err) => Ok(Response::error(err.to_string())),
     }
 }
 
-fn handle_get<E: KvsEngine>(engine: E, key: String) -> Result<Response> {
-    let get_result = engine.get(key);
+/// Answers with the value, its version and the time it was written.
+fn handle_get_meta<E: KvsEngine>(engine: E, key: Vec<u8>) -> Result<Response> {
+    let get_result = engine.get_with_meta_bytes(key);
     match get_result {
-        Ok(v) => match v {
-            Some(v) => Ok(Response {
-                res: true,
-                info: v + "\n",
-            }),
-            None => Ok(Response {
-                res: true,
-                info: "Key not found".to_string(),
-            }),
-        },
+        Ok(Some(versioned)) => {
+            let modified_at = versioned
+                .modified
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64);
+            Ok(Response {
+                version: Some(versioned.version),
+                modified_at: Some(modified_at),
+                ..Response::value(versioned.value)
+            })
+        }
+        Ok(None) => Ok(Response::info("Key not found")),
+        Err(err) => Ok(Response::error(err.to_string())),
+    }
+}
+
+fn handle_remove<E: KvsEngine>(engine: E, key: Vec<u8>) -> Result<Response> {
+    write_response(engine.remove_bytes(key))
+}
+
+fn handle_cas<E: KvsEngine>(
+    engine: E,
+    key: Vec<u8>,
+    expected: Option<Vec<u8>>,
+    new: Option<Vec<u8>>,
+) -> Result<Response> {
+    write_response(engine.compare_and_swap_bytes(key, expected, new))
+}
+
+fn handle_set_if<E: KvsEngine>(
+    engine: E,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    present: bool,
+) -> Result<Response> {
+    let set_result = match present {
+        true => engine.set_if_present_bytes(key, value),
+        false => engine.set_if_absent_bytes(key, value),
+    };
+    write_response(set_result)
+}
+
+/// Answers a write, telling a failed condition apart from other errors.
+fn write_response(write_result: Result<()>) -> Result<Response> {
+    match write_result {
+        Ok(_) => Ok(Response::ok()),
         Err(err) => Ok(Response {
-            res: false,
-            info: err.to_string(),
+            condition_failed: matches!(err, KvsError::ConditionFailed),
+            ..Response::error(err.to_string())
         }),
     }
 }
 
-fn handle_remove<E: KvsEngine>(engine: E, key: String) -> Result<Response> {
-    let rm_result = engine.remove(key);
-    match rm_result {
-        Ok(_) => Ok(Response {
-            res: true,
-            info: "".to_string(),
-        }),
-        Err(err) => Ok(Response {
-            res: false,
-            info: format!("{}", err),
+/// Answers with the whole seconds left before the key expires, rounded up.
+fn handle_ttl<E: KvsEngine>(engine: E, key: Vec<u8>) -> Result<Response> {
+    let ttl_result = engine.ttl_bytes(key);
+    match ttl_result {
+        Ok(Some(ttl)) => Ok(Response::value(
+            ttl.as_millis().div_ceil(1000).to_string().into_bytes(),
+        )),
+        Ok(None) => Ok(Response::info("No expiry")),
+        Err(err) => Ok(Response::error(err.to_string())),
+    }
+}
+
+fn handle_batch<E: KvsEngine>(engine: E, batch: WriteBatch) -> Result<Response> {
+    write_response(engine.apply_batch(batch))
+}
+
+fn handle_checkpoint<E: KvsEngine>(
+    engine: E,
+    checkpoint_root: Option<&Path>,
+    dir: String,
+) -> Result<Response> {
+    let root = match checkpoint_root {
+        Some(root) => root,
+        None => return Ok(Response::error("Checkpoints are disabled on this server")),
+    };
+    let dest_dir = match checkpoint_path(root, &dir) {
+        Some(dest_dir) => dest_dir,
+        None => {
+            return Ok(Response::error(format!(
+                "Invalid checkpoint directory {}: it must be a relative path without `..`",
+                dir
+            )))
+        }
+    };
+    info!("writing a checkpoint to {}", dest_dir.display());
+    write_response(engine.checkpoint(&dest_dir))
+}
+
+/// Resolves the directory a client named for a checkpoint under `root`,
+/// `None` if it is absolute or has `..` components and could lead outside of
+/// it.
+fn checkpoint_path(root: &Path, dir: &str) -> Option<PathBuf> {
+    let dir = Path::new(dir);
+    let mut components = dir.components().peekable();
+    components.peek()?;
+    components
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        .then(|| root.join(dir))
+}
+
+fn handle_stats<E: KvsEngine>(engine: E) -> Result<Response> {
+    let stats_result = engine.stats();
+    match stats_result {
+        Ok(stats) => Ok(Response {
+            stats: Some(stats),
+            ..Response::ok()
         }),
+        Err(err) => Ok(Response::error(err.to_string())),
+    }
+}
+
+/// Runs the transaction started by `Command::Begin`, answering the commands
+/// of the connection until it commits or aborts.
+fn handle_transaction<E: KvsEngine>(engine: E, stream: &TcpStream) -> Result<()> {
+    let mut tx = engine.begin();
+    serde_json::to_writer(stream, &transaction_response(Ok(None)))?;
+
+    let mut de = serde_json::Deserializer::from_reader(stream);
+    loop {
+        // Dropping the transaction with the connection aborts it.
+        let cmd = match Command::deserialize(&mut de) {
+            Ok(cmd) => cmd,
+            Err(_) => return Ok(()),
+        };
+        let res = match cmd {
+            Command::Get { key } => transaction_response(tx.get_bytes(key)),
+            Command::Set {
+                key,
+                value,
+                ttl_ms: None,
+                version: None,
+            } => transaction_response(tx.set_bytes(key, value).map(|()| None)),
+            Command::Rm { key, version: None } => {
+                transaction_response(tx.remove_bytes(key).map(|()| None))
+            }
+            Command::Commit => {
+                let res = transaction_response(tx.commit().map(|()| None));
+                serde_json::to_writer(stream, &res)?;
+                return Ok(());
+            }
+            Command::Abort => {
+                serde_json::to_writer(stream, &transaction_response(Ok(None)))?;
+                return Ok(());
+            }
+            _ => Response::error("Not allowed in a transaction"),
+        };
+        serde_json::to_writer(stream, &res)?;
+    }
+}
+
+/// Tells a conflict apart from other errors of a transaction.
+fn transaction_response(result: Result<Option<Vec<u8>>>) -> Response {
+    match result {
+        Ok(value) => Response {
+            value,
+            ..Response::ok()
+        },
+        Err(err) => Response {
+            conflict: matches!(err, KvsError::Conflict),
+            ..Response::error(err.to_string())
+        },
     }
 }
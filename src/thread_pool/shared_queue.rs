@@ -40,7 +40,7 @@ impl ThreadPool for SharedQueueThreadPool {
     }
 }
 
-fn start_thread(rx: &crossbeam::Receiver<Box<(dyn FnOnce() + Send + 'static)>>) -> Result<()> {
+fn start_thread(rx: &crossbeam::Receiver<Box<dyn FnOnce() + Send + 'static>>) -> Result<()> {
     let rx = rx.clone();
     thread::Builder::new()
         .spawn(move || {
@@ -53,7 +53,7 @@ fn start_thread(rx: &crossbeam::Receiver<Box<(dyn FnOnce() + Send + 'static)>>)
     Ok(())
 }
 
-fn run_task(rx: &crossbeam::Receiver<Box<(dyn FnOnce() + Send + 'static)>>) {
+fn run_task(rx: &crossbeam::Receiver<Box<dyn FnOnce() + Send + 'static>>) {
     loop {
         match rx.recv() {
             Ok(job) => job(),
@@ -1,41 +1,303 @@
 use serde::{Deserialize, Serialize};
+
+use crate::{EngineStats, WriteBatch};
 // use std::str::FromStr;
 
 /// data structure of KvStore operation for serialization and deserialization
+///
+/// Keys and values are bytes. They go over the wire as JSON strings when they
+/// are valid UTF-8 and as arrays of numbers otherwise, so that string commands
+/// keep their original format.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command {
-    /// Set the value of a string key to a string.
-    /// Return an error if the value is not written successfully.
+    /// Set the value of a key, until it expires if a TTL is given, only if
+    /// its current value was set by the write of `version` if one is given.
+    /// Return an error if the value is not written successfully, and a
+    /// response with `condition_failed` set if the version does not match.
     Set {
         /// key
-        key: String,
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
         /// value
-        value: String,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        /// time to live in milliseconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_ms: Option<u64>,
+        /// version the current value must have
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
     },
 
-    /// Get the string value of a string key. If the key does not exist, return None.
+    /// Get the value of a key. If the key does not exist, return None.
     /// Return an error if the value is not read successfully.
     Get {
         /// key
-        key: String,
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+    },
+
+    /// Get the value of a key with its version and the time it was written.
+    /// If the key does not exist, return None.
+    GetMeta {
+        /// key
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
     },
 
-    /// Remove a given string key.
+    /// Remove a given key, only if its current value was set by the write of
+    /// `version` if one is given.
     /// Return an error if the key does not exit or value is not read successfully.
     Rm {
         /// key
-        key: String,
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        /// version the current value must have
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+
+    /// Set the value of a key to `new`, or remove it if `new` is None, only if
+    /// its current value is `expected`, None meaning the key does not exist.
+    /// Return a response with `condition_failed` set if it is not.
+    Cas {
+        /// key
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        /// value the key must have
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "option_bytes"
+        )]
+        expected: Option<Vec<u8>>,
+        /// value to set
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "option_bytes"
+        )]
+        new: Option<Vec<u8>>,
+    },
+
+    /// Set the value of a key only if it does not exist.
+    /// Return a response with `condition_failed` set if it does.
+    SetIfAbsent {
+        /// key
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        /// value
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+    },
+
+    /// Set the value of a key only if it exists.
+    /// Return a response with `condition_failed` set if it does not.
+    SetIfPresent {
+        /// key
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        /// value
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+    },
+
+    /// Get the time left before a key expires.
+    /// Return an error if the key does not exist.
+    Ttl {
+        /// key
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+    },
+
+    /// Apply several writes all or nothing.
+    /// Return an error if the batch is not written successfully.
+    Batch {
+        /// writes of the batch
+        batch: WriteBatch,
+    },
+
+    /// Write a checkpoint of the data to a directory on the server side,
+    /// which must not exist yet.
+    /// Return an error if the checkpoint is not written successfully, or if
+    /// the server does not take checkpoints.
+    Checkpoint {
+        /// destination directory, relative to the checkpoint directory of
+        /// the server
+        dir: String,
     },
+    /// Start a transaction on the connection, which then stays open for the
+    /// `Get`, `Set` and `Rm` commands of the transaction until `Commit` or
+    /// `Abort`. Closing the connection aborts the transaction.
+    Begin,
+
+    /// Apply the writes of the transaction all or nothing.
+    /// Return a response with `conflict` set if a key it read has changed
+    /// since, in which case nothing is written.
+    Commit,
+
+    /// Drop the writes of the transaction.
+    Abort,
+
+    /// Admin command: get the number of keys, the size of the data and the
+    /// counters of the engine.
+    Stats,
 }
 
 /// data structure of response for serialization and deserialization
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Response {
     /// operation result
     /// true for success
     /// false for fail
     pub res: bool,
 
-    /// detail infomation for error or value
+    /// detail infomation for error
     pub info: String,
+
+    /// value found by a get
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "option_bytes"
+    )]
+    pub value: Option<Vec<u8>>,
+
+    /// true if a conditional write failed its condition
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub condition_failed: bool,
+
+    /// version of the value found by a get with metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+
+    /// when the value found by a get with metadata was written, in
+    /// milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<u64>,
+
+    /// true if a transaction conflicted with another write
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub conflict: bool,
+
+    /// figures returned by a stats command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<EngineStats>,
+}
+
+impl Response {
+    /// A successful response.
+    pub(crate) fn ok() -> Response {
+        Response {
+            res: true,
+            ..Response::default()
+        }
+    }
+
+    /// A successful response carrying `value`.
+    pub(crate) fn value(value: Vec<u8>) -> Response {
+        Response {
+            value: Some(value),
+            ..Response::ok()
+        }
+    }
+
+    /// A successful response without a value, telling why.
+    pub(crate) fn info(info: impl Into<String>) -> Response {
+        Response {
+            info: info.into(),
+            ..Response::ok()
+        }
+    }
+
+    /// A failed response, telling why.
+    pub(crate) fn error(info: impl Into<String>) -> Response {
+        Response {
+            res: false,
+            info: info.into(),
+            ..Response::default()
+        }
+    }
+}
+
+/// (De)serializes bytes as a string when they are valid UTF-8.
+pub(crate) mod bytes {
+    use std::fmt;
+    use std::str;
+
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<T: AsRef<[u8]>, S: Serializer>(
+        bytes: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let bytes = bytes.as_ref();
+        match str::from_utf8(bytes) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => serializer.serialize_bytes(bytes),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a string or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            Ok(v.as_bytes().to_vec())
+        }
+
+        fn visit_string<E: de::Error>(self, v: String) -> Result<Vec<u8>, E> {
+            Ok(v.into_bytes())
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+/// Same as `bytes`, for optional values.
+mod option_bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    struct Borrowed<'a>(#[serde(with = "super::bytes")] &'a [u8]);
+
+    #[derive(Deserialize)]
+    struct Owned(#[serde(with = "super::bytes")] Vec<u8>);
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.as_deref().map(Borrowed).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<Owned>::deserialize(deserializer)?.map(|Owned(bytes)| bytes))
+    }
 }
@@ -1,6 +1,16 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    KvStore, KvStoreOptions, KvsEngine, KvsError, KvsSnapshot, Problem, Result, ScanOptions,
+    SledKvsEngine, SledOptions, SyncPolicy, WriteBatch,
+};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -88,12 +98,18 @@ fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
 
+    // Compaction runs in the background and may remove a file while it is
+    // being measured.
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
         let len: walkdir::Result<u64> = entries
             .map(|res| {
                 res.and_then(|entry| entry.metadata())
                     .map(|metadata| metadata.len())
+                    .or_else(|err| match err.io_error() {
+                        Some(io_err) if io_err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+                        _ => Err(err),
+                    })
             })
             .sum();
         len.expect("fail to get directory size")
@@ -209,3 +225,2373 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Writes should roll over to new generations once a log file is full.
+#[test]
+fn segmented_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let log_count = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+        .count();
+    assert!(
+        log_count > 1,
+        "expected several log files, got {}",
+        log_count
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// A `log.json` written by older versions should still be readable.
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("log.json"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Rm":{"key":"key2"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(!temp_dir.path().join("log.json").exists());
+
+    Ok(())
+}
+
+// Logs written as JSON by older versions should be converted on open.
+#[test]
+fn migrate_json_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key1","value":"value2"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A damaged record should be reported instead of being misparsed.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Flip a byte of the first value. Records start after the 8-byte file header.
+    let log_path = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log_path)?;
+    bytes[8 + 13 + 4] ^= 0xff;
+    fs::write(&log_path, bytes)?;
+
+    let options = KvStoreOptions::default().strict(true);
+    match KvStore::open_with(temp_dir.path(), options) {
+        Err(KvsError::CorruptedLog { gen: 1, offset: 8 }) => Ok(()),
+        other => panic!(
+            "expected a corrupted log error, got {:?}",
+            other.map(|_| ())
+        ),
+    }
+}
+
+// A record cut short by a crash should be dropped when opening the store.
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let valid_len = fs::metadata(&log_path)?.len();
+    let file = fs::OpenOptions::new().write(true).open(&log_path)?;
+    file.set_len(valid_len - 3)?;
+    drop(file);
+
+    let options = KvStoreOptions::default().strict(true);
+    let second_offset = 8 + 13 + 24 + 10;
+    match KvStore::open_with(temp_dir.path(), options) {
+        Err(KvsError::CorruptedLog { gen: 1, offset }) => assert_eq!(offset, second_offset),
+        other => panic!(
+            "expected a corrupted log error, got {:?}",
+            other.map(|_| ())
+        ),
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Reads should keep seeing every key while compaction moves entries around.
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().segment_size(4096);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..20000 {
+                let key_id = (i + thread_id) % 100;
+                assert!(store.get(format!("key{}", key_id)).unwrap().is_some());
+            }
+        });
+        handles.push(handle);
+    }
+    for i in 0..30000 {
+        let key_id = i % 100;
+        store.set(format!("key{}", key_id), format!("value{}", i))?;
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// A store without stale records should be left alone, even when reopened.
+#[test]
+fn no_compaction_without_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+
+    assert!(temp_dir.path().join("1.log").exists());
+    Ok(())
+}
+
+// Overwriting keys should get the old logs compacted in the background.
+#[test]
+fn compaction_by_garbage_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .garbage_ratio(0.5)
+        .compaction_rate(None);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    for i in 0..2000 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+
+    let mut compacted = false;
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.log").exists() {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "the first log was never compacted");
+    assert_eq!(store.get("key".to_owned())?, Some("value1999".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value1999".to_owned()));
+
+    Ok(())
+}
+
+// Compacted logs should get a hint file that is used when reopening.
+#[test]
+fn open_with_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .compaction_rate(None);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 100), format!("value{}", i))?;
+    }
+
+    let hint_exists = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().extension().is_some_and(|ext| ext == "hint"))
+    };
+    let mut compacted = false;
+    for _ in 0..100 {
+        if hint_exists() {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "no hint file was written");
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..100 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", 1900 + i))
+            );
+        }
+        Ok(())
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    drop(store);
+
+    // An entry claiming a huge key is taken for damage, and the logs are
+    // replayed instead of the hint files.
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "hint") {
+            let mut bytes = fs::read(&path)?;
+            // Past the header and the checksum of the first entry, if any.
+            if bytes.len() >= 40 {
+                bytes[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+                fs::write(&path, bytes)?;
+            }
+        }
+    }
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Every sync policy should keep the written data.
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Always,
+        SyncPolicy::EveryMillis(10),
+        SyncPolicy::EveryBytes(100),
+        SyncPolicy::Os,
+    ];
+    for &sync_policy in &policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default()
+            .segment_size(1024)
+            .sync_policy(sync_policy);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        thread::sleep(Duration::from_millis(20));
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_binary_keys_and_values<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let key = vec![0xff, 0x00, 0xfe];
+    let value: Vec<u8> = (0..=255).collect();
+
+    let store = open()?;
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(b"text".to_vec(), vec![0xc3, 0x28])?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+    assert!(matches!(
+        store.get("text".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value));
+    store.remove_bytes(key.clone())?;
+    assert_eq!(store.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary_keys_and_values(|| KvStore::open(temp_dir.path()))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary_keys_and_values(|| SledKvsEngine::open(temp_dir.path()))
+}
+
+fn scan_keys<E: KvsEngine>(
+    store: &E,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    options: ScanOptions,
+) -> Result<Vec<String>> {
+    store
+        .scan(range, options)?
+        .map(|pair| pair.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect()
+}
+
+fn scan_keys_at<S: KvsSnapshot>(snapshot: &S, options: ScanOptions) -> Result<Vec<String>> {
+    snapshot
+        .scan(.., options)?
+        .map(|pair| pair.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect()
+}
+
+fn check_scans<E: KvsEngine>(store: E) -> Result<()> {
+    for key in &["b", "a", "ab", "abc", "b\u{ff}", "c", "ac"] {
+        store.set(key.to_string(), format!("value_{}", key))?;
+    }
+    store.remove("ac".to_owned())?;
+
+    let pairs: Vec<_> = store
+        .scan(b"ab".to_vec()..b"c".to_vec(), ScanOptions::default())?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"ab".to_vec(), b"value_ab".to_vec()),
+            (b"abc".to_vec(), b"value_abc".to_vec()),
+            (b"b".to_vec(), b"value_b".to_vec()),
+            ("b\u{ff}".into(), "value_b\u{ff}".into()),
+        ]
+    );
+
+    let all = (Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(
+        scan_keys(&store, all.clone(), ScanOptions::default().reverse(true))?,
+        vec!["c", "b\u{ff}", "b", "abc", "ab", "a"]
+    );
+    assert_eq!(
+        scan_keys(&store, all, ScanOptions::default().limit(2))?,
+        vec!["a", "ab"]
+    );
+    // Pages follow each other from the last key of the previous one.
+    let next_page = (Bound::Excluded(b"ab".to_vec()), Bound::Unbounded);
+    assert_eq!(
+        scan_keys(&store, next_page, ScanOptions::default().limit(2))?,
+        vec!["abc", "b"]
+    );
+    let inverted = (
+        Bound::Included(b"c".to_vec()),
+        Bound::Excluded(b"a".to_vec()),
+    );
+    assert!(scan_keys(&store, inverted, ScanOptions::default())?.is_empty());
+
+    let prefixed: Vec<_> = store
+        .scan_prefix(b"a".to_vec(), ScanOptions::default().reverse(true).limit(2))?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(prefixed, vec![b"abc".to_vec(), b"ab".to_vec()]);
+    let prefixed: Vec<_> = store
+        .scan_prefix(b"b".to_vec(), ScanOptions::default())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(prefixed, vec![b"b".to_vec(), "b\u{ff}".into()]);
+
+    Ok(())
+}
+
+#[test]
+fn ordered_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn scan_prefix_of_max_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(vec![0xfe, 0xff], b"1".to_vec())?;
+    store.set_bytes(vec![0xff], b"2".to_vec())?;
+    store.set_bytes(vec![0xff, 0xff, 0x00], b"3".to_vec())?;
+
+    let keys: Vec<_> = store
+        .scan_prefix(vec![0xff], ScanOptions::default())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![vec![0xff], vec![0xff, 0xff, 0x00]]);
+
+    Ok(())
+}
+
+// Scans take the index a chunk at a time, and pick up after the last key of a
+// chunk.
+#[test]
+fn scans_in_chunks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..2500 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    let expected: Vec<String> = (0..2500).map(|i| format!("key{:04}", i)).collect();
+    let reversed: Vec<String> = expected.iter().rev().cloned().collect();
+
+    let range = || (Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(
+        scan_keys(&store, range(), ScanOptions::default())?,
+        expected
+    );
+    assert_eq!(
+        scan_keys(&store, range(), ScanOptions::default().reverse(true))?,
+        reversed
+    );
+    assert_eq!(
+        scan_keys(&store, range(), ScanOptions::default().limit(2000))?,
+        expected[..2000]
+    );
+    let range = (
+        Bound::Excluded(b"key0100".to_vec()),
+        Bound::Included(b"key2200".to_vec()),
+    );
+    assert_eq!(
+        scan_keys(&store, range, ScanOptions::default().reverse(true))?,
+        reversed[299..2399]
+    );
+
+    // Keys removed ahead of the scan are skipped.
+    let mut scan = store.scan(.., ScanOptions::default())?;
+    assert_eq!(scan.next().unwrap()?.0, b"key0000".to_vec());
+    store.remove("key2000".to_owned())?;
+    assert_eq!(scan.count(), 2498);
+    Ok(())
+}
+
+fn log_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    names.sort();
+    names
+}
+
+// A crash during a compaction can leave temporary files, and the old logs
+// next to the compacted one. Opening the store cleans them up without bringing
+// removed keys back.
+#[test]
+fn recover_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(1024)
+        .compaction_rate(None);
+
+    // Nothing is compacted while the logs are filled.
+    let store = KvStore::open_with(temp_dir.path(), options.clone().garbage_ratio(1.0))?;
+    store.set("gone".to_owned(), "value".to_owned())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i % 50), format!("value{}", i))?;
+    }
+    store.remove("gone".to_owned())?;
+    drop(store);
+    let old_logs = log_files(temp_dir.path());
+    assert!(old_logs.len() > 2);
+    for name in &old_logs {
+        fs::copy(temp_dir.path().join(name), backup_dir.path().join(name))?;
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let mut compacted = false;
+    for _ in 0..100 {
+        if !temp_dir.path().join(&old_logs[0]).exists() {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "the logs were not compacted");
+    drop(store);
+
+    // The oldest log holds the value of "gone", a newer one its removal.
+    fs::copy(
+        backup_dir.path().join(&old_logs[0]),
+        temp_dir.path().join(&old_logs[0]),
+    )?;
+    fs::write(temp_dir.path().join("1000.log.tmp"), b"partial")?;
+    fs::write(temp_dir.path().join("1000.hint.tmp"), b"partial")?;
+    fs::write(temp_dir.path().join("1001.hint"), b"orphan")?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    for i in 450..500 {
+        assert_eq!(
+            store.get(format!("key{}", i % 50))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert!(!temp_dir.path().join(&old_logs[0]).exists());
+    for name in &["1000.log.tmp", "1000.hint.tmp", "1001.hint"] {
+        assert!(!temp_dir.path().join(name).exists(), "{} was kept", name);
+    }
+
+    Ok(())
+}
+
+fn crash_options() -> KvStoreOptions {
+    KvStoreOptions::default()
+        .segment_size(4096)
+        .compaction_rate(Some(256 * 1024))
+}
+
+// Run by `kill_during_compaction` in a child process that gets killed while it
+// writes and compacts. Does nothing when run with the other tests.
+#[test]
+fn compaction_crash_child() -> Result<()> {
+    let root = match env::var_os("KVS_CRASH_DIR") {
+        Some(root) => Path::new(&root).to_owned(),
+        None => return Ok(()),
+    };
+    let store = KvStore::open_with(root.join("store"), crash_options())?;
+    for i in 0..100 {
+        store.set(format!("stable{}", i), format!("value{}", i))?;
+        store.set(format!("gone{}", i), "value".to_owned())?;
+    }
+    for i in 0..100 {
+        store.remove(format!("gone{}", i))?;
+    }
+    fs::write(root.join("ready"), b"")?;
+
+    for round in 0.. {
+        for i in 0..100 {
+            store.set(format!("hot{}", i), format!("round{}", round))?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn kill_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let ready = temp_dir.path().join("ready");
+
+    for i in 0..5 {
+        let _ = fs::remove_file(&ready);
+        let mut child = Command::new(env::current_exe()?)
+            .args(["compaction_crash_child", "--exact", "--nocapture"])
+            .env("KVS_CRASH_DIR", temp_dir.path())
+            .stdout(Stdio::null())
+            .spawn()?;
+        for _ in 0..200 {
+            if ready.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(ready.exists(), "the child process did not start writing");
+        thread::sleep(Duration::from_millis(100 + 150 * i));
+        child.kill()?;
+        child.wait()?;
+
+        let store = KvStore::open_with(&store_dir, crash_options())?;
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("stable{}", i))?,
+                Some(format!("value{}", i))
+            );
+            assert_eq!(store.get(format!("gone{}", i))?, None);
+            if let Some(value) = store.get(format!("hot{}", i))? {
+                assert!(value.starts_with("round"), "unexpected value {}", value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Run by `failed_write_is_undone` in a child process whose files can't grow
+// past 512 bytes, so that a write to the log stops partway. Does nothing when
+// run with the other tests.
+#[test]
+fn failed_write_child() -> Result<()> {
+    let root = match env::var_os("KVS_FSIZE_DIR") {
+        Some(root) => Path::new(&root).to_owned(),
+        None => return Ok(()),
+    };
+    let log_path = root.join("store").join("1.log");
+    let store = KvStore::open(root.join("store"))?;
+    let mut written = 0;
+    loop {
+        let len = fs::metadata(&log_path)?.len();
+        if store
+            .set(format!("key{}", written), "v".repeat(100))
+            .is_err()
+        {
+            assert_eq!(fs::metadata(&log_path)?.len(), len);
+            break;
+        }
+        written += 1;
+    }
+    store.set("last".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    fs::write(root.join("written"), written.to_string())?;
+    Ok(())
+}
+
+// A write that fails partway is cut from the log, so the writes after it are
+// where the index expects them and survive a reopen.
+#[cfg(unix)]
+#[test]
+fn failed_write_is_undone() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg("trap '' XFSZ; ulimit -f 1; exec \"$0\" failed_write_child --exact --nocapture")
+        .arg(env::current_exe()?)
+        .env("KVS_FSIZE_DIR", temp_dir.path())
+        .stdout(Stdio::null())
+        .status()?;
+    assert!(status.success());
+
+    let written: usize = fs::read_to_string(temp_dir.path().join("written"))?
+        .parse()
+        .unwrap();
+    assert!(written > 0);
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    for i in 0..written {
+        assert_eq!(store.get(format!("key{}", i))?, Some("v".repeat(100)));
+    }
+    assert_eq!(store.get(format!("key{}", written))?, None);
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Only one store at a time can have a directory open for writing, in this
+// process or another. Read-only stores don't take the lock.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked { pid }) => assert_eq!(pid, std::process::id()),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+
+    let read_only = KvStore::open_with(temp_dir.path(), KvStoreOptions::default().read_only(true))?;
+    assert_eq!(read_only.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        read_only.set("key".to_owned(), "other".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        read_only.remove("key".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    // Clones keep the lock.
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+    // The KvStore of the same directory is locked out as well.
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+
+    // A read-only engine takes no lock, and reads a copy of the database as
+    // of its open.
+    let read_only_options = SledOptions::default().read_only(true);
+    let read_only = SledKvsEngine::open_with(temp_dir.path(), read_only_options)?;
+    assert_eq!(read_only.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        read_only.set("key".to_owned(), "other".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    engine.set("key".to_owned(), "other".to_owned())?;
+    assert_eq!(read_only.get("key".to_owned())?, Some("value".to_owned()));
+
+    drop(engine);
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key".to_owned())?, Some("other".to_owned()));
+    assert_eq!(read_only.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A read-only store sees the writes of the live writer once refreshed, also
+// after a compaction replaced the logs it had loaded.
+#[test]
+fn read_only_refresh() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .garbage_ratio(0.5)
+        .compaction_rate(None);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("gone".to_owned(), "value".to_owned())?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("gone".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    assert_eq!(reader.get("gone".to_owned())?, Some("value".to_owned()));
+    reader.refresh()?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(reader.get("gone".to_owned())?, None);
+
+    for i in 0..2000 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let mut compacted = false;
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.log").exists() {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "the first log was never compacted");
+    // The logs loaded before the compaction are still readable.
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1999".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(reader.get("gone".to_owned())?, None);
+    let keys: Vec<_> = reader
+        .scan(.., ScanOptions::default())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        keys,
+        vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()]
+    );
+
+    // Refreshing a writable store does nothing.
+    store.refresh()?;
+    Ok(())
+}
+
+// Opening read-only writes nothing: missing directories are not created and
+// damaged tails are not truncated.
+#[test]
+fn read_only_leaves_directory_untouched() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = temp_dir.path().join(&log_files(temp_dir.path())[0]);
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(b"torn record")?;
+    drop(file);
+    let len = fs::metadata(&log)?.len();
+    let mut names: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    names.sort();
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(reader);
+    assert_eq!(fs::metadata(&log)?.len(), len);
+    let mut after: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    after.sort();
+    assert_eq!(after, names);
+
+    // Reopening for writing drops the damaged tail.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(fs::metadata(&log)?.len() < len);
+    Ok(())
+}
+
+#[test]
+fn sled_open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    drop(engine);
+
+    let reader = SledKvsEngine::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        reader.remove("key2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    drop(reader);
+
+    // The database is not created.
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(SledKvsEngine::open_read_only(empty_dir.path()).is_err());
+    assert!(!empty_dir.path().join("sled.db").exists());
+    Ok(())
+}
+
+// Opening a sled database read-only and reading from it leaves every file of
+// the directory as it was, the lock file included.
+#[test]
+fn sled_read_only_leaves_directory_alone() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+
+    let files = |dir: &Path| -> Vec<(std::path::PathBuf, Vec<u8>)> {
+        WalkDir::new(dir)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .map(|entry| entry.expect("unable to walk the directory"))
+            .map(|entry| {
+                let contents = if entry.file_type().is_file() {
+                    fs::read(entry.path()).expect("unable to read a file")
+                } else {
+                    Vec::new()
+                };
+                (entry.path().to_owned(), contents)
+            })
+            .collect()
+    };
+    let before = files(temp_dir.path());
+    let reader = SledKvsEngine::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.ttl("key1".to_owned())?, None);
+    let snapshot = reader.snapshot()?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(snapshot);
+    drop(reader);
+    assert_eq!(files(temp_dir.path()), before);
+    Ok(())
+}
+
+// A checkpoint holds the data as of when it was taken, and opening it for
+// writing leaves the store it was taken from alone.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(1024)
+        .compaction_rate(None);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i % 50), format!("value{}", i))?;
+    }
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.remove("gone".to_owned())?;
+
+    let dest = checkpoint_dir.path().join("first");
+    store.checkpoint(&dest)?;
+    assert!(matches!(
+        store.checkpoint(&dest),
+        Err(KvsError::StringError(_))
+    ));
+    store.set("key0".to_owned(), "after".to_owned())?;
+
+    let copy = KvStore::open_with(&dest, options.clone())?;
+    for i in 450..500 {
+        assert_eq!(
+            copy.get(format!("key{}", i % 50))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(copy.get("gone".to_owned())?, None);
+    copy.set("key1".to_owned(), "copy".to_owned())?;
+    drop(copy);
+
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value451".to_owned()));
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value451".to_owned()));
+
+    // A read-only store copies what it has loaded.
+    store.set("key2".to_owned(), "loaded".to_owned())?;
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    store.set("key3".to_owned(), "not loaded".to_owned())?;
+    let dest = checkpoint_dir.path().join("read-only");
+    reader.checkpoint(&dest)?;
+    let copy = KvStore::open(&dest)?;
+    assert_eq!(copy.get("key2".to_owned())?, Some("loaded".to_owned()));
+    assert_eq!(copy.get("key3".to_owned())?, Some("value453".to_owned()));
+    Ok(())
+}
+
+// Checkpoints taken while writes and compactions go on are consistent: each
+// one holds all the writes made before it started.
+#[test]
+fn checkpoint_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(1024)
+        .garbage_ratio(0.3)
+        .compaction_rate(None);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 1..=2000 {
+                store.set("counter".to_owned(), i.to_string())?;
+                store.set(format!("key{}", i % 20), i.to_string())?;
+            }
+            Ok(())
+        })
+    };
+
+    for n in 0..10 {
+        let before: u32 = store.get("counter".to_owned())?.unwrap().parse().unwrap();
+        let dest = checkpoint_dir.path().join(n.to_string());
+        store.checkpoint(&dest)?;
+        let copy = KvStore::open(&dest)?;
+        let counter: u32 = copy.get("counter".to_owned())?.unwrap().parse().unwrap();
+        assert!(counter >= before);
+    }
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn sled_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_bytes(vec![0, 255], vec![1, 2, 3])?;
+
+    let dest = checkpoint_dir.path().join("sled");
+    engine.checkpoint(&dest)?;
+    engine.set("key1".to_owned(), "after".to_owned())?;
+
+    let copy = SledKvsEngine::open(&dest)?;
+    assert_eq!(copy.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(copy.get_bytes(vec![0, 255])?, Some(vec![1, 2, 3]));
+    assert_eq!(engine.get("key1".to_owned())?, Some("after".to_owned()));
+    Ok(())
+}
+
+fn check_expiring_keys(engine: &impl KvsEngine) -> Result<()> {
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set_with_ttl(
+        "persisted".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set("persisted".to_owned(), "forever".to_owned())?;
+    assert_eq!(engine.get("short".to_owned())?, Some("value".to_owned()));
+    let ttl = engine.ttl("long".to_owned())?.unwrap();
+    assert!(ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600));
+    assert_eq!(engine.ttl("persisted".to_owned())?, None);
+    assert!(matches!(
+        engine.ttl("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert!(matches!(
+        engine.ttl("short".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        engine.remove("short".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    let keys: Vec<_> = engine
+        .scan(.., ScanOptions::default())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"long".to_vec(), b"persisted".to_vec()]);
+    assert_eq!(
+        engine.get("persisted".to_owned())?,
+        Some("forever".to_owned())
+    );
+
+    // Setting an expired key starts it over.
+    engine.set("short".to_owned(), "again".to_owned())?;
+    assert_eq!(engine.get("short".to_owned())?, Some("again".to_owned()));
+    assert_eq!(engine.ttl("short".to_owned())?, None);
+    engine.remove("short".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_expiring_keys(&store)?;
+    store.set_with_ttl(
+        "restart".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    drop(store);
+
+    // Expiries survive a restart.
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = store.ttl("long".to_owned())?.unwrap();
+    assert!(ttl > Duration::from_secs(3590));
+    assert_eq!(store.get("restart".to_owned())?, Some("value".to_owned()));
+    thread::sleep(Duration::from_millis(300));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("restart".to_owned())?, None);
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(
+        store.get("persisted".to_owned())?,
+        Some("forever".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn sled_expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions::default().sweep_interval(Duration::from_millis(50));
+    let engine = SledKvsEngine::open_with(temp_dir.path(), options)?;
+    check_expiring_keys(&engine)?;
+    engine.set_with_ttl(
+        "swept".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(300));
+    drop(engine);
+
+    // The sweeper removed the expired keys from the database.
+    let db = sled::open(temp_dir.path().join("sled.db"))?;
+    assert!(!db.contains_key("swept")?);
+    assert!(db.contains_key("long")?);
+    assert_eq!(db.open_tree("kvs-expiry")?.len(), 1);
+    Ok(())
+}
+
+// Expired keys count as stale once swept, so compactions reclaim them. Hint
+// files keep the expiries.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .garbage_ratio(0.5)
+        .compaction_rate(None)
+        .sweep_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let value = "v".repeat(100);
+    for i in 0..200 {
+        store.set_with_ttl(
+            format!("expiring{}", i),
+            value.clone(),
+            Duration::from_millis(200),
+        )?;
+    }
+    store.set_with_ttl("kept".to_owned(), value.clone(), Duration::from_secs(3600))?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let mut compacted = false;
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.log").exists() {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "the first log was never compacted");
+    let log_bytes: u64 = log_files(temp_dir.path())
+        .iter()
+        .map(|name| fs::metadata(temp_dir.path().join(name)).unwrap().len())
+        .sum();
+    assert!(
+        log_bytes < 200 * 100,
+        "expired keys were kept: {}",
+        log_bytes
+    );
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("expiring0".to_owned())?, None);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    let ttl = store.ttl("kept".to_owned())?.unwrap();
+    assert!(ttl > Duration::from_secs(3590));
+    Ok(())
+}
+
+// Concurrent writers with per-write syncs share appends, and every one of them
+// sees the outcome of its own write.
+#[test]
+fn concurrent_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let barrier = Arc::new(Barrier::new(16));
+    let handles: Vec<_> = (0..16)
+        .map(|t| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for i in 0..50 {
+                    store.set(format!("key{}_{}", t, i), format!("value{}", i))?;
+                    if i % 5 == 0 {
+                        store.remove(format!("key{}_{}", t, i))?;
+                        assert!(matches!(
+                            store.remove(format!("key{}_{}", t, i)),
+                            Err(KvsError::KeyNotFound)
+                        ));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for t in 0..16 {
+        for i in 0..50 {
+            let expected = if i % 5 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("key{}_{}", t, i))?, expected);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_write_batches(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("old".to_owned(), "value".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key1".to_vec(), b"value1".to_vec())
+        .set(b"key2".to_vec(), b"value2".to_vec())
+        .set(b"key1".to_vec(), b"again".to_vec())
+        .set_with_ttl(
+            b"short".to_vec(),
+            b"value".to_vec(),
+            Duration::from_millis(100),
+        )
+        .remove(b"old".to_vec())
+        .remove(b"missing".to_vec());
+    assert_eq!(batch.len(), 6);
+    engine.apply_batch(batch)?;
+    engine.apply_batch(WriteBatch::new())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("again".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("old".to_owned())?, None);
+    assert!(engine.ttl("short".to_owned())?.is_some());
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get("short".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn write_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_write_batches(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("old".to_owned())?, None);
+
+    let read_only = KvStore::open_read_only(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    assert!(matches!(
+        read_only.apply_batch(batch),
+        Err(KvsError::ReadOnly)
+    ));
+    Ok(())
+}
+
+#[test]
+fn sled_write_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    check_write_batches(&engine)?;
+    drop(engine);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("again".to_owned()));
+    assert_eq!(engine.get("old".to_owned())?, None);
+    Ok(())
+}
+
+// A batch whose commit marker never made it to the log should be dropped as a
+// whole when opening the store, even though its records are complete.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key1".to_vec(), b"batched".to_vec())
+        .set(b"key2".to_vec(), b"batched".to_vec());
+    store.apply_batch(batch)?;
+    drop(store);
+
+    // Cut the commit marker, a bare header.
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    let file = OpenOptions::new().write(true).open(&log_path)?;
+    file.set_len(len - 13)?;
+    drop(file);
+
+    let options = KvStoreOptions::default().strict(true);
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), options),
+        Err(KvsError::CorruptedLog { gen: 1, .. })
+    ));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn check_conditional_writes<E: KvsEngine>(engine: &E) -> Result<()> {
+    let condition_failed = |result: Result<()>| matches!(result, Err(KvsError::ConditionFailed));
+
+    engine.set_if_absent("key1".to_owned(), "value1".to_owned())?;
+    assert!(condition_failed(
+        engine.set_if_absent("key1".to_owned(), "other".to_owned())
+    ));
+    engine.set_if_present("key1".to_owned(), "value2".to_owned())?;
+    assert!(condition_failed(
+        engine.set_if_present("key2".to_owned(), "value2".to_owned())
+    ));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    assert!(condition_failed(engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value3".to_owned()),
+    )));
+    assert!(condition_failed(engine.compare_and_swap(
+        "key1".to_owned(),
+        None,
+        Some("value3".to_owned()),
+    )));
+    engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value2".to_owned()),
+        Some("value3".to_owned()),
+    )?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    engine.compare_and_swap("key1".to_owned(), Some("value3".to_owned()), None)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.compare_and_swap("key1".to_owned(), None, None)?;
+    engine.compare_and_swap("key1".to_owned(), None, Some("value4".to_owned()))?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    // Expired keys don't exist, and a swap clears the expiry.
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    engine.compare_and_swap(
+        "short".to_owned(),
+        Some("value".to_owned()),
+        Some("kept".to_owned()),
+    )?;
+    assert_eq!(engine.ttl("short".to_owned())?, None);
+    engine.set_with_ttl(
+        "gone".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get("short".to_owned())?, Some("kept".to_owned()));
+    assert!(condition_failed(
+        engine.set_if_present("gone".to_owned(), "again".to_owned())
+    ));
+    engine.set_if_absent("gone".to_owned(), "again".to_owned())?;
+
+    // Concurrent increments through compare-and-swap lose no update.
+    let thread_count = 8;
+    let increments = 50;
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..thread_count)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..increments {
+                    loop {
+                        let current = engine.get("counter".to_owned())?.unwrap();
+                        let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                        match engine.compare_and_swap(
+                            "counter".to_owned(),
+                            Some(current),
+                            Some(next),
+                        ) {
+                            Err(KvsError::ConditionFailed) => continue,
+                            result => break result?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(
+        engine.get("counter".to_owned())?,
+        Some((thread_count * increments).to_string())
+    );
+    Ok(())
+}
+
+#[test]
+fn conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_conditional_writes(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, Some("again".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    check_conditional_writes(&engine)?;
+    Ok(())
+}
+
+fn check_versions<E: KvsEngine>(engine: &E) -> Result<()> {
+    let condition_failed = |result: Result<()>| matches!(result, Err(KvsError::ConditionFailed));
+    let before = SystemTime::now() - Duration::from_secs(1);
+
+    assert_eq!(engine.get_with_meta("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let first = engine.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(first.value, "value1");
+    assert!(first.version > 0);
+    assert!(first.modified >= before && first.modified <= SystemTime::now());
+
+    // Every write gets a higher version, whatever the key.
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    let second = engine.get_with_meta("key2".to_owned())?.unwrap();
+    assert!(second.version > first.version);
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    let third = engine.get_with_meta("key1".to_owned())?.unwrap();
+    assert!(third.version > second.version);
+
+    // Writes with a stale version fail and consume nothing.
+    assert!(condition_failed(engine.set_if_version(
+        "key1".to_owned(),
+        "stale".to_owned(),
+        first.version,
+    )));
+    assert!(condition_failed(
+        engine.remove_if_version("key1".to_owned(), first.version)
+    ));
+    assert!(condition_failed(engine.set_if_version(
+        "missing".to_owned(),
+        "value".to_owned(),
+        first.version,
+    )));
+    assert_eq!(
+        engine.get_with_meta("key1".to_owned())?,
+        Some(third.clone())
+    );
+
+    engine.set_if_version("key1".to_owned(), "value4".to_owned(), third.version)?;
+    let fourth = engine.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(fourth.value, "value4");
+    assert!(fourth.version > third.version);
+    engine.remove_if_version("key1".to_owned(), fourth.version)?;
+    assert_eq!(engine.get_with_meta("key1".to_owned())?, None);
+
+    // Removals take a version too, so a key set again gets a newer one.
+    engine.set("key1".to_owned(), "value5".to_owned())?;
+    let fifth = engine.get_with_meta("key1".to_owned())?.unwrap();
+    assert!(fifth.version > fourth.version + 1);
+
+    // Batches give each of their writes a version.
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"value6".to_vec());
+    batch.set(b"key2".to_vec(), b"value6".to_vec());
+    engine.apply_batch(batch)?;
+    let batched1 = engine.get_with_meta("key1".to_owned())?.unwrap();
+    let batched2 = engine.get_with_meta("key2".to_owned())?.unwrap();
+    assert!(batched1.version > fifth.version);
+    assert!(batched2.version > batched1.version);
+    Ok(())
+}
+
+#[test]
+fn versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_versions(&store)?;
+    let before = store.get_with_meta("key2".to_owned())?.unwrap();
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_meta("key2".to_owned())?,
+        Some(before.clone())
+    );
+    store.set("key3".to_owned(), "value".to_owned())?;
+    let after = store.get_with_meta("key3".to_owned())?.unwrap();
+    assert!(after.version > before.version);
+    Ok(())
+}
+
+#[test]
+fn sled_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    check_versions(&engine)?;
+    let before = engine.get_with_meta("key2".to_owned())?.unwrap();
+    drop(engine);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(
+        engine.get_with_meta("key2".to_owned())?,
+        Some(before.clone())
+    );
+    engine.set("key3".to_owned(), "value".to_owned())?;
+    let after = engine.get_with_meta("key3".to_owned())?.unwrap();
+    assert!(after.version > before.version);
+    Ok(())
+}
+
+// Compaction drops removals, but their versions must not be given out again.
+#[test]
+fn versions_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .garbage_ratio(0.5)
+        .compaction_rate(None);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let writes = 2000;
+    for i in 0..writes / 2 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+        store.remove("key".to_owned())?;
+    }
+    let mut compacted = false;
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.log").exists() {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "the first log was never compacted");
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let versioned = store.get_with_meta("key".to_owned())?.unwrap();
+    assert!(versioned.version > writes);
+    Ok(())
+}
+
+fn check_snapshots<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    let snapshot = engine.snapshot()?;
+    let version = engine.get_with_meta("key3".to_owned())?.unwrap().version;
+    assert_eq!(snapshot.version(), version);
+
+    engine.set("key1".to_owned(), "new1".to_owned())?;
+    engine.set("key1".to_owned(), "newer1".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.remove(b"key3".to_vec());
+    batch.set(b"key2".to_vec(), b"new2".to_vec());
+    engine.apply_batch(batch)?;
+    let later = engine.snapshot()?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(snapshot.get("key4".to_owned())?, None);
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = snapshot
+        .scan(.., ScanOptions::default())?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key3".to_vec(), b"value3".to_vec()),
+        ]
+    );
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = snapshot
+        .scan(.., ScanOptions::default().reverse(true).limit(2))?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key3".to_vec(), b"value3".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = later
+        .scan(.., ScanOptions::default())?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"newer1".to_vec()),
+            (b"key2".to_vec(), b"new2".to_vec()),
+            (b"key4".to_vec(), b"value4".to_vec()),
+        ]
+    );
+    assert_eq!(engine.get("key1".to_owned())?, Some("newer1".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    // A scan outlives its snapshot.
+    let scan = snapshot.scan_prefix(b"key".to_vec(), ScanOptions::default())?;
+    drop(snapshot);
+    engine.set("key1".to_owned(), "newest1".to_owned())?;
+    assert_eq!(scan.count(), 3);
+    assert_eq!(later.get("key1".to_owned())?, Some("newer1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_snapshots(&store)
+}
+
+#[test]
+fn sled_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    check_snapshots(&engine)
+}
+
+// Scans of a snapshot go through the index and the replaced entries a chunk at
+// a time, and chunks of either may end before the other.
+#[test]
+fn snapshot_scans_in_chunks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..3000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    let snapshot = store.snapshot()?;
+    for i in 0..3000 {
+        match i % 3 {
+            0 => store.remove(format!("key{:04}", i))?,
+            1 => store.set(format!("key{:04}", i), "new".to_owned())?,
+            _ => {}
+        }
+    }
+    for i in 3000..4000 {
+        store.set(format!("key{:04}", i), "new".to_owned())?;
+    }
+
+    let expected: Vec<String> = (0..3000).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(scan_keys_at(&snapshot, ScanOptions::default())?, expected);
+    let reversed: Vec<String> = expected.iter().rev().cloned().collect();
+    assert_eq!(
+        scan_keys_at(&snapshot, ScanOptions::default().reverse(true))?,
+        reversed
+    );
+    assert_eq!(
+        scan_keys_at(&snapshot, ScanOptions::default().reverse(true).limit(1500))?,
+        reversed[..1500]
+    );
+    let (key, value) = snapshot
+        .scan(.., ScanOptions::default().limit(1))?
+        .next()
+        .unwrap()?;
+    assert_eq!((key, value), (b"key0000".to_vec(), b"value0".to_vec()));
+    Ok(())
+}
+
+// sled keeps the states writes replace only as long as a snapshot needs them.
+#[test]
+fn sled_snapshot_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let older = engine.snapshot()?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    let newer = engine.snapshot()?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    engine.remove("key1".to_owned())?;
+
+    drop(older);
+    assert_eq!(newer.get("key1".to_owned())?, Some("value2".to_owned()));
+    let scan = newer.scan(.., ScanOptions::default().reverse(true))?;
+    drop(newer);
+    assert_eq!(scan.count(), 1);
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    drop(engine);
+
+    let db = sled::open(temp_dir.path().join("sled.db"))?;
+    assert!(db.open_tree("kvs-history")?.is_empty());
+    Ok(())
+}
+
+// Compaction keeps the logs a snapshot reads from until it is dropped.
+#[test]
+fn snapshots_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .garbage_ratio(0.5)
+        .compaction_rate(None)
+        .sweep_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let snapshot = store.snapshot()?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 100), format!("new{}", i))?;
+    }
+
+    let hint_exists = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().extension().is_some_and(|ext| ext == "hint"))
+    };
+    let mut compacted = false;
+    for _ in 0..100 {
+        if hint_exists() {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "no hint file was written");
+    for i in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("new{}", 1900 + i))
+        );
+    }
+    assert_eq!(snapshot.scan(.., ScanOptions::default())?.count(), 100);
+    assert!(temp_dir.path().join("1.log").exists());
+
+    drop(snapshot);
+    let mut removed = false;
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.log").exists() {
+            removed = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(removed, "the log kept for the snapshot was never removed");
+    for i in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("new{}", 1900 + i))
+        );
+    }
+    Ok(())
+}
+
+fn check_transactions<E: KvsEngine>(engine: &E) -> Result<()> {
+    let conflict = |result: Result<()>| matches!(result, Err(KvsError::Conflict));
+
+    // A transaction reads its own writes, which no one else sees until it
+    // commits.
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let mut tx = engine.begin();
+    assert_eq!(tx.get("key1".to_owned())?, Some("value1".to_owned()));
+    tx.set("key1".to_owned(), "value2".to_owned())?;
+    tx.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(tx.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        tx.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    tx.commit()?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // A transaction whose reads changed before it commits writes nothing.
+    let mut tx = engine.begin();
+    assert_eq!(tx.get("key1".to_owned())?, Some("value2".to_owned()));
+    tx.remove("key2".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(matches!(tx.get("key1".to_owned()), Err(KvsError::Conflict)));
+    assert!(conflict(tx.commit()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // So does one that found a key missing that exists by then.
+    let mut tx = engine.begin();
+    assert_eq!(tx.get("key3".to_owned())?, None);
+    tx.set("key3".to_owned(), "mine".to_owned())?;
+    engine.set("key3".to_owned(), "theirs".to_owned())?;
+    assert!(conflict(tx.commit()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("theirs".to_owned()));
+
+    // Dropping a transaction aborts it.
+    let mut tx = engine.begin();
+    tx.set("key4".to_owned(), "value4".to_owned())?;
+    drop(tx);
+    assert_eq!(engine.get("key4".to_owned())?, None);
+
+    // Concurrent transfers between accounts, retried on conflict, keep the
+    // total.
+    for i in 0..4 {
+        engine.set(format!("account{}", i), "100".to_owned())?;
+    }
+    let balance = |value: Option<String>| value.unwrap().parse::<i64>().unwrap();
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let engine = engine.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for i in 0..20 {
+                    let from = format!("account{}", (t + i) % 4);
+                    let to = format!("account{}", (t + i + 1) % 4);
+                    engine.transaction(|tx| {
+                        let from_balance = balance(tx.get(from.clone())?);
+                        let to_balance = balance(tx.get(to.clone())?);
+                        tx.set(from.clone(), (from_balance - 1).to_string())?;
+                        tx.set(to.clone(), (to_balance + 1).to_string())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let mut total = 0;
+    for i in 0..4 {
+        total += balance(engine.get(format!("account{}", i))?);
+    }
+    assert_eq!(total, 400);
+
+    // The result of the closure is returned once it commits.
+    let read = engine.transaction(|tx| tx.get("key1".to_owned()))?;
+    assert_eq!(read, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_transactions(&store)?;
+
+    // Committed transactions are in the log like any other write.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    check_transactions(&engine)
+}
+
+fn check_bounded_index(store: &KvStore) -> Result<()> {
+    for i in 0..5000 {
+        let expected = match i % 50 {
+            0 => None,
+            _ => Some(format!("value{}", 15000 + i)),
+        };
+        assert_eq!(store.get(format!("key{:05}", i))?, expected);
+    }
+
+    let all = (Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(
+        scan_keys(store, all.clone(), ScanOptions::default())?.len(),
+        4900
+    );
+    assert_eq!(
+        scan_keys(store, all, ScanOptions::default().reverse(true).limit(3))?,
+        vec!["key04999", "key04998", "key04997"]
+    );
+    let range = (
+        Bound::Included(b"key01000".to_vec()),
+        Bound::Excluded(b"key01100".to_vec()),
+    );
+    let keys = scan_keys(store, range, ScanOptions::default())?;
+    assert_eq!(keys.len(), 98);
+    assert_eq!(keys.first().map(String::as_str), Some("key01001"));
+    assert_eq!(keys.last().map(String::as_str), Some("key01099"));
+    Ok(())
+}
+
+// A bounded index should keep its keys in the key directory file, and be
+// rebuilt from the logs when the file is lost or damaged.
+#[test]
+fn bounded_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(64 * 1024)
+        .compaction_rate(None)
+        .index_memory_budget(Some(64 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for i in 0..20000 {
+        store.set(format!("key{:05}", i % 5000), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        store.remove(format!("key{:05}", i * 50))?;
+    }
+    let keydir = temp_dir.path().join("keydir");
+    for _ in 0..100 {
+        if keydir.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(keydir.exists(), "no key directory file was written");
+    check_bounded_index(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    check_bounded_index(&store)?;
+    drop(store);
+
+    fs::remove_file(&keydir)?;
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    check_bounded_index(&store)?;
+    drop(store);
+
+    let file = OpenOptions::new().write(true).open(&keydir)?;
+    file.set_len(file.metadata()?.len() - 4)?;
+    drop(file);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check_bounded_index(&store)?;
+    drop(store);
+
+    // Without a budget, the file is of no use and goes away.
+    let store = KvStore::open(temp_dir.path())?;
+    check_bounded_index(&store)?;
+    assert!(!keydir.exists());
+    Ok(())
+}
+
+#[test]
+fn bounded_index_snapshots_and_transactions() -> Result<()> {
+    let options = KvStoreOptions::default().index_memory_budget(Some(0));
+    let checks: [fn(&KvStore) -> Result<()>; 3] = [check_snapshots, check_transactions, |store| {
+        check_expiring_keys(store)
+    }];
+    for check in checks {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+    }
+    Ok(())
+}
+
+// The value cache should serve repeated reads, follow writes and survive
+// compactions.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .compaction_rate(None)
+        .value_cache_size(Some(64 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.cache_stats().map(|stats| stats.entries), Some(0));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+    // Writes are seen right away.
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Compactions move the values without dropping them from the cache.
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    for i in 0..2000 {
+        store.set("key3".to_owned(), format!("value{}", i))?;
+    }
+    let mut compacted = false;
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.log").exists() {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "no compaction happened");
+    let hits = store.cache_stats().unwrap().hits;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.cache_stats().unwrap().hits, hits + 1);
+    Ok(())
+}
+
+// The value cache should evict the least recently used values to stay within
+// its size.
+#[test]
+fn value_cache_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().value_cache_size(Some(4096));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), "x".repeat(200))?;
+    }
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("x".repeat(200)));
+    }
+    let stats = store.cache_stats().unwrap();
+    assert!(stats.size <= 4096);
+    assert!(stats.entries > 0 && stats.entries < 100);
+
+    // The most recent reads are still cached, the first ones are not.
+    let hits = stats.hits;
+    store.get("key99".to_owned())?;
+    assert_eq!(store.cache_stats().unwrap().hits, hits + 1);
+    store.get("key0".to_owned())?;
+    assert_eq!(store.cache_stats().unwrap().hits, hits + 1);
+
+    // Values larger than the cache are not cached at all.
+    store.set("big".to_owned(), "x".repeat(8192))?;
+    store.get("big".to_owned())?;
+    store.get("big".to_owned())?;
+    assert_eq!(store.cache_stats().unwrap().hits, hits + 1);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(KvStore::open(temp_dir.path())?.cache_stats(), None);
+    Ok(())
+}
+
+fn check_stats<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.stats()?.keys, 0);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.set(b"key4".to_vec(), b"value4".to_vec());
+    engine.apply_batch(batch)?;
+    assert!(engine.remove("missing".to_owned()).is_err());
+
+    engine.get("key1".to_owned())?;
+    engine.get("missing".to_owned())?;
+    engine.get_with_meta("key3".to_owned())?;
+    engine.ttl("key4".to_owned())?;
+    engine.scan(.., ScanOptions::default())?.count();
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 3);
+    assert!(stats.total_bytes > 0);
+    assert_eq!(stats.reads, 5);
+    assert_eq!(stats.writes, 5);
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_stats(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_stats(SledKvsEngine::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().index_memory_budget(Some(64 * 1024));
+    check_stats(KvStore::open_with(temp_dir.path(), options)?)
+}
+
+// Stale bytes build up with overwrites, and compactions reclaim them.
+#[test]
+fn stats_compactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .garbage_ratio(0.5)
+        .compaction_rate(None);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let stats = store.stats()?;
+    assert!(stats.stale_bytes > 0 && stats.stale_bytes < stats.total_bytes);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction, None);
+
+    for i in 0..2000 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let mut compacted = false;
+    for _ in 0..100 {
+        if store.stats()?.compactions > 0 {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "no compaction was counted");
+    let stats = store.stats()?;
+    assert!(stats.last_compaction.is_some());
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.writes, 2002);
+
+    // Counters start over, the byte counts are rebuilt from the logs.
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.keys, 1);
+    assert_eq!(reopened.compactions, 0);
+    assert_eq!(reopened.writes, 0);
+    assert!(reopened.total_bytes > 0);
+    Ok(())
+}
+
+// Values in the sealed logs are read through maps, which compactions replace
+// along with the logs.
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .garbage_ratio(0.5)
+        .compaction_rate(None)
+        .mmap_reads(true);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    let reader = KvStore::open_with(temp_dir.path(), options.clone().read_only(true))?;
+    assert_eq!(reader.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    for i in 0..2000 {
+        store.set("churn".to_owned(), format!("value{}", i))?;
+    }
+    let mut compacted = false;
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.log").exists() {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(compacted, "the first log was never compacted");
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // The reader still has the old logs mapped until it refreshes.
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    reader.refresh()?;
+    for i in 0..200 {
+        assert_eq!(
+            reader.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    drop(reader);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("churn".to_owned())?, Some("value1999".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.remove(b"key2".to_vec());
+    store.apply_batch(batch)?;
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_secs(60),
+    )?;
+
+    // The store is open for writing.
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.live);
+    assert!(report.is_ok(), "{:?}", report.problems);
+    drop(store);
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.live);
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.logs, 1);
+    assert_eq!(report.keys, 3);
+    assert!(report.stale_bytes > 0);
+    assert!(report.stale_ratio() > 0.0 && report.stale_ratio() < 1.0);
+
+    // Flip a byte of the first value.
+    let log_path = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log_path)?;
+    bytes[8 + 13 + 4] ^= 0xff;
+    fs::write(&log_path, bytes)?;
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(
+        report.problems,
+        vec![Problem::DamagedLog {
+            gen: 1,
+            offset: 8,
+            reason: "checksum mismatch".to_owned(),
+        }]
+    );
+
+    assert!(KvStore::verify(temp_dir.path().join("missing")).is_err());
+    Ok(())
+}
+
+// Verifying neither creates nor writes to the lock file, so that stores on
+// read-only mounts can be checked. Without a lock file to lock, the store is
+// taken to be open for writing.
+#[cfg(unix)]
+#[test]
+fn verify_read_only_dir() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let lock_path = temp_dir.path().join("kvs.lock");
+    let lock_contents = fs::read(&lock_path)?;
+    let set_mode = |path: &Path, mode| fs::set_permissions(path, fs::Permissions::from_mode(mode));
+
+    set_mode(&lock_path, 0o444)?;
+    set_mode(temp_dir.path(), 0o555)?;
+    let locked = KvStore::verify(temp_dir.path());
+    assert_eq!(fs::read(&lock_path)?, lock_contents);
+    set_mode(temp_dir.path(), 0o755)?;
+    fs::remove_file(&lock_path)?;
+    set_mode(temp_dir.path(), 0o555)?;
+    let unlocked = KvStore::verify(temp_dir.path());
+    set_mode(temp_dir.path(), 0o755)?;
+
+    let locked = locked?;
+    assert!(!locked.live);
+    assert!(locked.is_ok(), "{:?}", locked.problems);
+    assert_eq!(locked.keys, 1);
+    let unlocked = unlocked?;
+    assert!(unlocked.live);
+    assert!(unlocked.is_ok(), "{:?}", unlocked.problems);
+    assert!(!lock_path.exists());
+    Ok(())
+}
+
+// Hint files are checked against the records of their logs.
+#[test]
+fn verify_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .segment_size(4096)
+        .compaction_rate(None);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 100), format!("value{}", i))?;
+    }
+    let hint_path = || -> Option<std::path::PathBuf> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "hint"))
+    };
+    for _ in 0..100 {
+        if hint_path().is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    drop(store);
+    // Opening again settles a compaction left unfinished by the drop.
+    drop(KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default().compaction_threshold(u64::MAX),
+    )?);
+    let hint = hint_path().expect("no hint file was written");
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(report.hints >= 1);
+    assert_eq!(report.keys, 100);
+
+    // Damage the magic number of the hint file, as it may have no entries.
+    let mut bytes = fs::read(&hint)?;
+    bytes[0] ^= 0xff;
+    fs::write(&hint, bytes)?;
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(
+        report
+            .problems
+            .iter()
+            .any(|problem| matches!(problem, Problem::DamagedHint { .. })),
+        "{:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+#[test]
+fn parse_sync_policy() {
+    assert_eq!(
+        "always".parse::<SyncPolicy>().ok(),
+        Some(SyncPolicy::Always)
+    );
+    assert_eq!("os".parse::<SyncPolicy>().ok(), Some(SyncPolicy::Os));
+    assert_eq!(
+        "100ms".parse::<SyncPolicy>().ok(),
+        Some(SyncPolicy::EveryMillis(100))
+    );
+    assert_eq!(
+        "4096bytes".parse::<SyncPolicy>().ok(),
+        Some(SyncPolicy::EveryBytes(4096))
+    );
+    assert!("0ms".parse::<SyncPolicy>().is_err());
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+}